@@ -0,0 +1,26 @@
+-- Also adopts tables created by hand from earlier versions of the README
+CREATE TABLE IF NOT EXISTS documents (
+    id BIGSERIAL PRIMARY KEY,
+    embedding real[],
+    raw TEXT,
+    doc_ref TEXT,
+    segment bigint
+);
+ALTER TABLE documents ADD COLUMN IF NOT EXISTS start_offset bigint;
+ALTER TABLE documents ADD COLUMN IF NOT EXISTS end_offset bigint;
+ALTER TABLE documents ADD COLUMN IF NOT EXISTS start_line int;
+ALTER TABLE documents ADD COLUMN IF NOT EXISTS end_line int;
+ALTER TABLE documents ADD COLUMN IF NOT EXISTS metadata jsonb NOT NULL DEFAULT '{}';
+ALTER TABLE documents ADD COLUMN IF NOT EXISTS embedding_model TEXT;
+ALTER TABLE documents ADD COLUMN IF NOT EXISTS embedding_dim int;
+CREATE INDEX IF NOT EXISTS documents_metadata_idx ON documents USING gin(metadata);
+CREATE INDEX IF NOT EXISTS documents_doc_ref_idx ON documents (doc_ref, segment);
+
+-- Rows from before models were recorded were all made with the original model
+UPDATE documents SET embedding_model = 'all-MiniLM-L6-v2', embedding_dim = 384 WHERE embedding_model IS NULL;
+
+CREATE TABLE IF NOT EXISTS embedding_cache (
+    key TEXT PRIMARY KEY,
+    embedding_model TEXT NOT NULL,
+    embedding real[] NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Lexical side of hybrid search, catches identifiers and codes embeddings blur
+ALTER TABLE documents ADD COLUMN IF NOT EXISTS raw_tsv tsvector
+    GENERATED ALWAYS AS (to_tsvector('english', coalesce(raw, ''))) STORED;
+CREATE INDEX IF NOT EXISTS documents_raw_tsv_idx ON documents USING gin(raw_tsv);
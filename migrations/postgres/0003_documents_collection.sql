@@ -0,0 +1,7 @@
+-- Documents belong to a named collection, everything ingested before collections is the default one
+ALTER TABLE documents ADD COLUMN IF NOT EXISTS collection TEXT NOT NULL DEFAULT 'default';
+DROP INDEX IF EXISTS documents_doc_ref_idx;
+CREATE INDEX IF NOT EXISTS documents_collection_doc_ref_idx ON documents (collection, doc_ref, segment);
+
+-- Replaced by a nearest neighbour index per collection, built on start-up for its dimension
+DROP INDEX IF EXISTS documents_embedding_idx;
@@ -0,0 +1,33 @@
+CREATE TABLE IF NOT EXISTS documents (
+    id INTEGER PRIMARY KEY,
+    embedding BLOB NOT NULL,
+    raw TEXT NOT NULL,
+    doc_ref TEXT NOT NULL,
+    segment INTEGER NOT NULL,
+    start_offset INTEGER,
+    end_offset INTEGER,
+    start_line INTEGER,
+    end_line INTEGER,
+    metadata TEXT NOT NULL DEFAULT '{}',
+    embedding_model TEXT,
+    embedding_dim INTEGER
+);
+CREATE INDEX IF NOT EXISTS documents_doc_ref_idx ON documents (doc_ref, segment);
+
+CREATE TABLE IF NOT EXISTS embedding_cache (
+    key TEXT PRIMARY KEY,
+    embedding_model TEXT NOT NULL,
+    embedding BLOB NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS ingest_index (
+    path TEXT PRIMARY KEY,
+    modified INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS chat_history (
+    id INTEGER PRIMARY KEY,
+    session TEXT NOT NULL,
+    message TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS chat_history_session_idx ON chat_history (session, id);
@@ -0,0 +1,15 @@
+-- Documents belong to a named collection, everything ingested before collections is the default one
+ALTER TABLE documents ADD COLUMN collection TEXT NOT NULL DEFAULT 'default';
+DROP INDEX IF EXISTS documents_doc_ref_idx;
+CREATE INDEX IF NOT EXISTS documents_collection_doc_ref_idx ON documents (collection, doc_ref, segment);
+
+-- Each collection keeps its own record of ingested files
+CREATE TABLE ingest_index_new (
+    collection TEXT NOT NULL,
+    path TEXT NOT NULL,
+    modified INTEGER NOT NULL,
+    PRIMARY KEY (collection, path)
+);
+INSERT INTO ingest_index_new (collection, path, modified) SELECT 'default', path, modified FROM ingest_index;
+DROP TABLE ingest_index;
+ALTER TABLE ingest_index_new RENAME TO ingest_index;
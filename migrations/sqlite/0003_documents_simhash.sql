@@ -0,0 +1,2 @@
+-- SimHash of `raw` for near-duplicate detection at ingest, computed from `raw` where missing
+ALTER TABLE documents ADD COLUMN simhash INTEGER;
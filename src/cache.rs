@@ -0,0 +1,81 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::store::{StoreError, VectorStore};
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Content addressed store of vectors, keyed by a hash of what produced them (the model, its
+/// backend and endpoint and the vector size) and the text, so a vector is only ever reused for
+/// the settings that produced it.
+pub struct EmbeddingCache {
+    store: Arc<dyn VectorStore>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl EmbeddingCache {
+    pub fn new(store: Arc<dyn VectorStore>) -> Self {
+        EmbeddingCache {
+            store,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn key(source: &str, raw: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(source.as_bytes());
+        hasher.update([0u8]);
+        hasher.update(raw.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Looks up every text, `None` for the ones that still have to be encoded.
+    pub async fn get(
+        &self,
+        source: &str,
+        raw: &[String],
+    ) -> Result<Vec<Option<Vec<f32>>>, StoreError> {
+        let keys: Vec<String> = raw.iter().map(|raw| Self::key(source, raw)).collect();
+        let found = self.store.cached(&keys).await?;
+        let embeddings: Vec<Option<Vec<f32>>> =
+            keys.iter().map(|key| found.get(key).cloned()).collect();
+        let hits = embeddings.iter().filter(|e| e.is_some()).count() as u64;
+        self.hits.fetch_add(hits, Ordering::Relaxed);
+        self.misses
+            .fetch_add(embeddings.len() as u64 - hits, Ordering::Relaxed);
+        Ok(embeddings)
+    }
+
+    pub async fn put(
+        &self,
+        model: &str,
+        source: &str,
+        raw: &[String],
+        embeddings: &[Vec<f32>],
+    ) -> Result<(), StoreError> {
+        let entries = raw
+            .iter()
+            .zip(embeddings)
+            .map(|(raw, embedding)| (Self::key(source, raw), embedding.clone()))
+            .collect();
+        self.store.cache(model, entries).await
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1,15 +1,19 @@
-use sqlx::{Pool, Postgres};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
-    env,
-    io::{self, Write},
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    io::{self, BufReader, Write},
+    path::Path,
+    sync::Arc,
 };
 
-use tokio::sync::mpsc::UnboundedSender;
-
 use crate::{
     completion::Completion,
-    schemas::{DocumentRef, EncodingRequest, OpenAiCompletionMessage},
-    util::{generate_embedding_for_text, sort_embeddings},
+    reranker::RerankSender,
+    retrieval::{retrieve, search_question},
+    schemas::{Collection, OpenAiCompletionMessage, RetrievalOptions, Source},
+    store::{parse_date, Distance, Filter, VectorStore},
 };
 
 #[derive(Debug, Default, PartialEq)]
@@ -19,17 +23,580 @@ pub enum Mode {
     Online,
 }
 
+#[derive(Debug, Default, PartialEq)]
+pub enum Command {
+    #[default]
+    Serve,
+    // Downloads the embedding model into `EMBEDDING_MODEL_DIR` and verifies it loads
+    FetchModel,
+    // Rebuilds vectors made by other models with the configured one
+    Reembed,
+    // Applies pending schema migrations
+    Migrate,
+    // Scores retrieval against a set of questions with known answers
+    Eval,
+}
+
 #[derive(Default, Debug)]
 pub struct Config {
+    pub command: Command,
     pub path: String,
     pub mode: Mode,
     pub index: String,
+    pub splitter: SplitterConfig,
+    pub dedup: DedupConfig,
+    pub retrieval: RetrievalConfig,
+    pub embedding: EmbeddingConfig,
+    pub distance: Distance,
+    pub vector: VectorConfig,
+    // Directory of the cross-encoder used to rerank search results
+    pub reranker_model_dir: Option<String>,
+    // Print what retrieval did, e.g. rewritten questions
+    pub debug: bool,
+    // Apply pending migrations on start-up instead of refusing to start
+    pub auto_migrate: bool,
+    // Chat history of the REPL is kept under this name by stores that keep history
+    pub session: String,
+    // Every knowledge base served, the settings above are the defaults of each
+    pub collections: Vec<CollectionConfig>,
+    // Asked when a request or REPL session doesn't choose a collection
+    pub collection: String,
+    // JSONL file of labelled questions scored by `eval`
+    pub eval_set: String,
+    // Where `eval` writes its JSON report
+    pub eval_output: Option<String>,
+}
+
+/// A knowledge base of its own: where its files are, how they are split and embedded, where the
+/// record of ingested files is kept and how questions against it are answered by default.
+#[derive(Debug, Clone)]
+pub struct CollectionConfig {
+    pub name: String,
+    pub path: String,
+    pub index: String,
+    pub splitter: SplitterConfig,
+    pub dedup: DedupConfig,
+    pub embedding: EmbeddingConfig,
+    // Metric vectors are compared by, with an index of its own
+    pub distance: Distance,
+    pub retrieval: RetrievalConfig,
+}
+
+/// An entry of the `COLLECTIONS` file, whatever is left out is taken from the global settings.
+#[derive(Debug, Default, Deserialize)]
+struct CollectionSettings {
+    data_dir: Option<String>,
+    index_path: Option<String>,
+    semantic_split: Option<Vec<String>>,
+    semantic_threshold: Option<f32>,
+    semantic_window: Option<usize>,
+    min_chunk_size: Option<usize>,
+    max_chunk_size: Option<usize>,
+    dedup: Option<String>,
+    dedup_distance: Option<u32>,
+    embedding_backend: Option<String>,
+    embedding_model: Option<String>,
+    embedding_dim: Option<usize>,
+    embedding_url: Option<String>,
+    embedding_model_dir: Option<String>,
+    embedding_cache: Option<bool>,
+    distance: Option<String>,
+    #[serde(flatten)]
+    retrieval: RetrievalOptions,
+}
+
+// Collection names end up in index names, so they stick to what SQL identifiers allow
+fn check_collection_name(name: &str) {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid {
+        panic!("Invalid collection name {name}, use lowercase letters, digits and _");
+    }
+}
+
+// Collections of the `COLLECTIONS` file, or a single `default` one made of the global settings
+fn load_collections(file: &str, config: &Config) -> Vec<CollectionConfig> {
+    let base = CollectionConfig {
+        name: DEFAULT_COLLECTION.to_string(),
+        path: config.path.clone(),
+        index: config.index.clone(),
+        splitter: config.splitter.clone(),
+        dedup: config.dedup.clone(),
+        embedding: config.embedding.clone(),
+        distance: config.distance,
+        retrieval: config.retrieval.clone(),
+    };
+    if file.is_empty() {
+        return vec![base];
+    }
+    let reader = BufReader::new(fs::File::open(file).expect("Unable to open COLLECTIONS"));
+    let settings: BTreeMap<String, CollectionSettings> =
+        serde_json::from_reader(reader).expect("Invalid COLLECTIONS");
+    settings
+        .into_iter()
+        .map(|(name, settings)| {
+            check_collection_name(&name);
+            // Each collection gets a directory and index file of its own next to the global ones
+            let path = settings.data_dir.unwrap_or_else(|| {
+                if base.path.is_empty() {
+                    String::new()
+                } else {
+                    Path::new(&base.path)
+                        .join(&name)
+                        .to_string_lossy()
+                        .to_string()
+                }
+            });
+            let index = settings.index_path.unwrap_or_else(|| {
+                if base.index.is_empty() {
+                    String::new()
+                } else {
+                    format!("{}.{name}", base.index)
+                }
+            });
+            let mut splitter = base.splitter.clone();
+            if let Some(semantic) = settings.semantic_split {
+                splitter.semantic = semantic
+                    .iter()
+                    .map(|ext| ext.trim_start_matches('.').to_string())
+                    .collect();
+            }
+            splitter.threshold = settings.semantic_threshold.unwrap_or(splitter.threshold);
+            splitter.window = settings.semantic_window.unwrap_or(splitter.window);
+            splitter.min_chunk = settings.min_chunk_size.unwrap_or(splitter.min_chunk);
+            splitter.max_chunk = settings.max_chunk_size.unwrap_or(splitter.max_chunk);
+            let mut dedup = base.dedup.clone();
+            if let Some(mode) = settings.dedup {
+                dedup.mode = parse_dedup(&mode);
+            }
+            dedup.distance = settings.dedup_distance.unwrap_or(dedup.distance);
+            let mut embedding = base.embedding.clone();
+            if let Some(backend) = settings.embedding_backend {
+                embedding.backend = parse_backend(&backend);
+            }
+            if let Some(model) = settings.embedding_model {
+                // The dimension of the global model says nothing about this one
+                embedding.model = model;
+                embedding.dimension = None;
+                embedding.model_dir = None;
+            }
+            embedding.dimension = settings.embedding_dim.or(embedding.dimension);
+            embedding.url = settings.embedding_url.unwrap_or(embedding.url);
+            embedding.model_dir = settings.embedding_model_dir.or(embedding.model_dir);
+            embedding.cache = settings.embedding_cache.unwrap_or(embedding.cache);
+            CollectionConfig {
+                name,
+                path,
+                index,
+                splitter,
+                dedup,
+                embedding,
+                distance: settings
+                    .distance
+                    .map_or(base.distance, |distance| parse_distance(&distance)),
+                retrieval: base.retrieval.with(&settings.retrieval),
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum EmbeddingBackend {
+    #[default]
+    RustBert,
+    // Any server implementing OpenAI's `/v1/embeddings`
+    OpenAi,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmbeddingConfig {
+    pub backend: EmbeddingBackend,
+    pub model: String,
+    // Required when it can't be inferred from the model
+    pub dimension: Option<usize>,
+    pub url: String,
+    // Directory to load a rust-bert model from instead of downloading it at start-up
+    pub model_dir: Option<String>,
+    // Reuse vectors of texts that were encoded before
+    pub cache: bool,
+    // Maximum number of texts encoded in a single call to the model
+    pub batch_size: usize,
+    // Number of requests that can wait on each of the query and ingestion queues
+    pub queue_size: usize,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        EmbeddingConfig {
+            backend: EmbeddingBackend::default(),
+            model: "all-MiniLM-L6-v2".to_string(),
+            dimension: None,
+            url: "https://api.openai.com/v1/embeddings".to_string(),
+            model_dir: None,
+            cache: true,
+            batch_size: 32,
+            queue_size: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VectorExtension {
+    PgVector,
+    // Neon's pg_embedding, deprecated in favour of pgvector
+    PgEmbedding,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum VectorIndex {
+    #[default]
+    Hnsw,
+    // pgvector only
+    IvfFlat,
+}
+
+#[derive(Debug, Clone)]
+pub struct VectorConfig {
+    // Detected from the installed extensions when not set
+    pub extension: Option<VectorExtension>,
+    pub index: VectorIndex,
+    // Candidates an HNSW search keeps, higher is slower with better recall
+    pub ef_search: Option<u32>,
+    // IVFFlat lists searched per query
+    pub probes: Option<u32>,
+    // IVFFlat lists the index is built with
+    pub lists: u32,
+}
+
+impl Default for VectorConfig {
+    fn default() -> Self {
+        VectorConfig {
+            extension: None,
+            index: VectorIndex::default(),
+            ef_search: None,
+            probes: None,
+            lists: 100,
+        }
+    }
+}
+
+fn parse_distance(value: &str) -> Distance {
+    match value {
+        "l2" => Distance::Euclidean,
+        "cosine" => Distance::Cosine,
+        "dot" => Distance::Dot,
+        _ => panic!("Unknown distance {value}, expected l2, cosine or dot"),
+    }
+}
+
+fn parse_extension(value: &str) -> Option<VectorExtension> {
+    match value {
+        "auto" => None,
+        "pgvector" => Some(VectorExtension::PgVector),
+        "pg_embedding" => Some(VectorExtension::PgEmbedding),
+        _ => panic!("Unknown vector extension {value}, expected auto, pgvector or pg_embedding"),
+    }
+}
+
+fn parse_index(value: &str) -> VectorIndex {
+    match value {
+        "hnsw" => VectorIndex::Hnsw,
+        "ivfflat" => VectorIndex::IvfFlat,
+        _ => panic!("Unknown vector index {value}, expected hnsw or ivfflat"),
+    }
+}
+
+fn parse_backend(value: &str) -> EmbeddingBackend {
+    match value {
+        "rust-bert" => EmbeddingBackend::RustBert,
+        "openai" => EmbeddingBackend::OpenAi,
+        _ => panic!("Unknown embedding backend {value}, expected rust-bert or openai"),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SplitterConfig {
+    // File extensions that are split on topic shifts instead of fixed size separators
+    pub semantic: Vec<String>,
+    pub threshold: f32,
+    pub min_chunk: usize,
+    pub max_chunk: usize,
+    pub window: usize,
+}
+
+impl Default for SplitterConfig {
+    fn default() -> Self {
+        SplitterConfig {
+            semantic: vec![],
+            threshold: 0.5,
+            min_chunk: 128 * 4,
+            max_chunk: 512 * 4,
+            window: 2,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum DedupMode {
+    // Store every segment
+    #[default]
+    Off,
+    // Leave out segments that repeat one already stored
+    Skip,
+    // Leave them out, referencing them from the segment they repeat
+    Merge,
+}
+
+fn parse_dedup(value: &str) -> DedupMode {
+    match value {
+        "off" => DedupMode::Off,
+        "skip" => DedupMode::Skip,
+        "merge" => DedupMode::Merge,
+        _ => panic!("Unknown dedup mode {value}, expected off, skip or merge"),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DedupConfig {
+    pub mode: DedupMode,
+    // Bits the SimHashes of near-duplicates may differ in, out of 64
+    pub distance: u32,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            mode: DedupMode::default(),
+            distance: 3,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueryStrategy {
+    // Search for the question as asked
+    #[default]
+    Single,
+    // Also search for paraphrases of the question
+    MultiQuery,
+    // Also search for a hypothetical answer passage
+    Hyde,
+}
+
+fn parse_strategy(value: &str) -> QueryStrategy {
+    match value {
+        "single" => QueryStrategy::Single,
+        "multi-query" => QueryStrategy::MultiQuery,
+        "hyde" => QueryStrategy::Hyde,
+        _ => panic!("Unknown query strategy {value}, expected single, multi-query or hyde"),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetrievalConfig {
+    // Number of segments fetched from the store
+    pub top_k: usize,
+    // Segments less similar to the question than this are dropped
+    pub min_similarity: Option<f32>,
+    // Maximum number of characters of segments pasted into the prompt
+    pub context_budget: Option<usize>,
+    // Segments before and after each hit added to the prompt
+    pub neighbours: usize,
+    // Fuse full-text search results with vector search results
+    pub hybrid: bool,
+    pub vector_weight: f32,
+    pub text_weight: f32,
+    // Rescore a larger candidate pool with the cross-encoder and keep the best `top_k`
+    pub rerank: bool,
+    pub rerank_candidates: usize,
+    // Pick `top_k` diverse segments from a larger candidate pool, trading relevance for novelty
+    // by `mmr_lambda` (1 is pure relevance)
+    pub mmr: bool,
+    pub mmr_lambda: f32,
+    pub mmr_candidates: usize,
+    // Rewrite follow-ups into standalone questions from the chat history before searching
+    pub rewrite: bool,
+    pub strategy: QueryStrategy,
+    // Paraphrases searched for by the multi-query strategy
+    pub paraphrases: usize,
 }
 
+impl Default for RetrievalConfig {
+    fn default() -> Self {
+        RetrievalConfig {
+            top_k: 4,
+            min_similarity: None,
+            context_budget: None,
+            neighbours: 0,
+            hybrid: false,
+            vector_weight: 1.0,
+            text_weight: 1.0,
+            rerank: false,
+            rerank_candidates: 20,
+            mmr: false,
+            mmr_lambda: 0.5,
+            mmr_candidates: 20,
+            rewrite: true,
+            strategy: QueryStrategy::default(),
+            paraphrases: 3,
+        }
+    }
+}
+
+impl RetrievalConfig {
+    /// These settings with whatever a request overrides.
+    pub fn with(&self, options: &RetrievalOptions) -> RetrievalConfig {
+        RetrievalConfig {
+            top_k: options.top_k.unwrap_or(self.top_k).clamp(1, MAX_TOP_K),
+            min_similarity: options.min_similarity.or(self.min_similarity),
+            context_budget: options.context_budget.or(self.context_budget),
+            neighbours: options
+                .neighbours
+                .unwrap_or(self.neighbours)
+                .min(MAX_NEIGHBOURS),
+            hybrid: options.hybrid.unwrap_or(self.hybrid),
+            vector_weight: self.vector_weight,
+            text_weight: self.text_weight,
+            rerank: options.rerank.unwrap_or(self.rerank),
+            rerank_candidates: self.rerank_candidates,
+            mmr: options.mmr.unwrap_or(self.mmr),
+            mmr_lambda: options
+                .mmr_lambda
+                .unwrap_or(self.mmr_lambda)
+                .clamp(0f32, 1f32),
+            mmr_candidates: self.mmr_candidates,
+            rewrite: options.rewrite.unwrap_or(self.rewrite),
+            strategy: options.strategy.unwrap_or(self.strategy),
+            paraphrases: self.paraphrases,
+        }
+    }
+}
+
+// Upper bounds for settings from requests
+const MAX_TOP_K: usize = 100;
+const MAX_NEIGHBOURS: usize = 5;
+
+// Collection of everything ingested before collections, and of setups without a COLLECTIONS file
+const DEFAULT_COLLECTION: &str = "default";
+
 pub fn parse_args() -> Config {
     let path_from_env = env::var("DATA_DIR").unwrap_or("".to_string());
-    let mut config = Config::default();
+    let mut config = Config {
+        session: "repl".to_string(),
+        collection: env::var("COLLECTION").unwrap_or(DEFAULT_COLLECTION.to_string()),
+        ..Config::default()
+    };
     let index_path = env::var("INDEX_PATH").unwrap_or("".to_string());
+    let mut collections_file = env::var("COLLECTIONS").unwrap_or("".to_string());
+    if let Ok(extensions) = env::var("SEMANTIC_SPLIT") {
+        config.splitter.semantic = parse_list(&extensions);
+    }
+    if let Ok(distance) = env::var("DISTANCE") {
+        config.distance = parse_distance(&distance);
+    }
+    if let Ok(dedup) = env::var("DEDUP") {
+        config.dedup.mode = parse_dedup(&dedup);
+    }
+    if let Ok(distance) = env::var("DEDUP_DISTANCE") {
+        config.dedup.distance = distance.parse().expect("Invalid DEDUP_DISTANCE");
+    }
+    if let Ok(top_k) = env::var("TOP_K") {
+        config.retrieval.top_k = top_k.parse().expect("Invalid TOP_K");
+    }
+    if let Ok(min_similarity) = env::var("MIN_SIMILARITY") {
+        config.retrieval.min_similarity =
+            Some(min_similarity.parse().expect("Invalid MIN_SIMILARITY"));
+    }
+    if let Ok(budget) = env::var("CONTEXT_BUDGET") {
+        config.retrieval.context_budget = Some(budget.parse().expect("Invalid CONTEXT_BUDGET"));
+    }
+    if let Ok(neighbours) = env::var("NEIGHBOURS") {
+        config.retrieval.neighbours = neighbours.parse().expect("Invalid NEIGHBOURS");
+    }
+    if let Ok(hybrid) = env::var("HYBRID_SEARCH") {
+        config.retrieval.hybrid = hybrid.parse().expect("Invalid HYBRID_SEARCH");
+    }
+    if let Ok(weight) = env::var("HYBRID_VECTOR_WEIGHT") {
+        config.retrieval.vector_weight = weight.parse().expect("Invalid HYBRID_VECTOR_WEIGHT");
+    }
+    if let Ok(weight) = env::var("HYBRID_TEXT_WEIGHT") {
+        config.retrieval.text_weight = weight.parse().expect("Invalid HYBRID_TEXT_WEIGHT");
+    }
+    if let Ok(rerank) = env::var("RERANK") {
+        config.retrieval.rerank = rerank.parse().expect("Invalid RERANK");
+    }
+    if let Ok(candidates) = env::var("RERANK_CANDIDATES") {
+        config.retrieval.rerank_candidates = candidates.parse().expect("Invalid RERANK_CANDIDATES");
+    }
+    if let Ok(mmr) = env::var("MMR") {
+        config.retrieval.mmr = mmr.parse().expect("Invalid MMR");
+    }
+    if let Ok(lambda) = env::var("MMR_LAMBDA") {
+        config.retrieval.mmr_lambda = lambda.parse().expect("Invalid MMR_LAMBDA");
+    }
+    if let Ok(candidates) = env::var("MMR_CANDIDATES") {
+        config.retrieval.mmr_candidates = candidates.parse().expect("Invalid MMR_CANDIDATES");
+    }
+    if let Ok(rewrite) = env::var("QUERY_REWRITE") {
+        config.retrieval.rewrite = rewrite.parse().expect("Invalid QUERY_REWRITE");
+    }
+    if let Ok(strategy) = env::var("QUERY_STRATEGY") {
+        config.retrieval.strategy = parse_strategy(&strategy);
+    }
+    if let Ok(paraphrases) = env::var("QUERY_PARAPHRASES") {
+        config.retrieval.paraphrases = paraphrases.parse().expect("Invalid QUERY_PARAPHRASES");
+    }
+    if let Ok(debug) = env::var("DEBUG") {
+        config.debug = debug.parse().expect("Invalid DEBUG");
+    }
+    if let Ok(model_dir) = env::var("RERANKER_MODEL_DIR") {
+        config.reranker_model_dir = Some(model_dir);
+    }
+    if let Ok(auto_migrate) = env::var("AUTO_MIGRATE") {
+        config.auto_migrate = auto_migrate.parse().expect("Invalid AUTO_MIGRATE");
+    }
+    if let Ok(extension) = env::var("VECTOR_EXTENSION") {
+        config.vector.extension = parse_extension(&extension);
+    }
+    if let Ok(index) = env::var("VECTOR_INDEX") {
+        config.vector.index = parse_index(&index);
+    }
+    if let Ok(ef_search) = env::var("VECTOR_EF_SEARCH") {
+        config.vector.ef_search = Some(ef_search.parse().expect("Invalid VECTOR_EF_SEARCH"));
+    }
+    if let Ok(probes) = env::var("VECTOR_PROBES") {
+        config.vector.probes = Some(probes.parse().expect("Invalid VECTOR_PROBES"));
+    }
+    if let Ok(lists) = env::var("VECTOR_LISTS") {
+        config.vector.lists = lists.parse().expect("Invalid VECTOR_LISTS");
+    }
+    if let Ok(backend) = env::var("EMBEDDING_BACKEND") {
+        config.embedding.backend = parse_backend(&backend);
+    }
+    if let Ok(model) = env::var("EMBEDDING_MODEL") {
+        config.embedding.model = model;
+    }
+    if let Ok(dimension) = env::var("EMBEDDING_DIM") {
+        config.embedding.dimension = Some(dimension.parse().expect("Invalid EMBEDDING_DIM"));
+    }
+    if let Ok(url) = env::var("EMBEDDING_URL") {
+        config.embedding.url = url;
+    }
+    if let Ok(model_dir) = env::var("EMBEDDING_MODEL_DIR") {
+        config.embedding.model_dir = Some(model_dir);
+    }
+    if let Ok(cache) = env::var("EMBEDDING_CACHE") {
+        config.embedding.cache = cache.parse().expect("Invalid EMBEDDING_CACHE");
+    }
+    if let Ok(batch_size) = env::var("EMBEDDING_BATCH_SIZE") {
+        config.embedding.batch_size = batch_size.parse().expect("Invalid EMBEDDING_BATCH_SIZE");
+    }
+    if let Ok(queue_size) = env::var("EMBEDDING_QUEUE_SIZE") {
+        config.embedding.queue_size = queue_size.parse().expect("Invalid EMBEDDING_QUEUE_SIZE");
+    }
     let args: Vec<String> = env::args().skip(1).collect();
     args.iter().for_each(|arg| {
         let (key, mut value) = arg.split_at(arg.find("=").unwrap_or(0));
@@ -42,34 +609,185 @@ pub fn parse_args() -> Config {
             if value == "offline" {
                 config.mode = Mode::Offline;
             }
+        } else if key == "--semantic-split" {
+            config.splitter.semantic = parse_list(value);
+        } else if key == "--semantic-threshold" {
+            config.splitter.threshold = value.parse().expect("Invalid --semantic-threshold");
+        } else if key == "--semantic-window" {
+            config.splitter.window = value.parse().expect("Invalid --semantic-window");
+        } else if key == "--min-chunk-size" {
+            config.splitter.min_chunk = value.parse().expect("Invalid --min-chunk-size");
+        } else if key == "--max-chunk-size" {
+            config.splitter.max_chunk = value.parse().expect("Invalid --max-chunk-size");
+        } else if key == "--dedup" {
+            config.dedup.mode = parse_dedup(value);
+        } else if key == "--dedup-distance" {
+            config.dedup.distance = value.parse().expect("Invalid --dedup-distance");
+        } else if key == "--session" {
+            config.session = value.to_string();
+        } else if key == "--collections" {
+            collections_file = value.to_string();
+        } else if key == "--collection" {
+            config.collection = value.to_string();
+        } else if key == "--distance" {
+            config.distance = parse_distance(value);
+        } else if key == "--top-k" {
+            config.retrieval.top_k = value.parse().expect("Invalid --top-k");
+        } else if key == "--min-similarity" {
+            config.retrieval.min_similarity =
+                Some(value.parse().expect("Invalid --min-similarity"));
+        } else if key == "--context-budget" {
+            config.retrieval.context_budget =
+                Some(value.parse().expect("Invalid --context-budget"));
+        } else if key == "--neighbours" {
+            config.retrieval.neighbours = value.parse().expect("Invalid --neighbours");
+        } else if key == "--hybrid-search" {
+            config.retrieval.hybrid = value.parse().expect("Invalid --hybrid-search");
+        } else if key == "--hybrid-vector-weight" {
+            config.retrieval.vector_weight = value.parse().expect("Invalid --hybrid-vector-weight");
+        } else if key == "--hybrid-text-weight" {
+            config.retrieval.text_weight = value.parse().expect("Invalid --hybrid-text-weight");
+        } else if key == "--rerank" {
+            config.retrieval.rerank = value.parse().expect("Invalid --rerank");
+        } else if key == "--rerank-candidates" {
+            config.retrieval.rerank_candidates =
+                value.parse().expect("Invalid --rerank-candidates");
+        } else if key == "--mmr" {
+            config.retrieval.mmr = value.parse().expect("Invalid --mmr");
+        } else if key == "--mmr-lambda" {
+            config.retrieval.mmr_lambda = value.parse().expect("Invalid --mmr-lambda");
+        } else if key == "--mmr-candidates" {
+            config.retrieval.mmr_candidates = value.parse().expect("Invalid --mmr-candidates");
+        } else if key == "--query-rewrite" {
+            config.retrieval.rewrite = value.parse().expect("Invalid --query-rewrite");
+        } else if key == "--query-strategy" {
+            config.retrieval.strategy = parse_strategy(value);
+        } else if key == "--query-paraphrases" {
+            config.retrieval.paraphrases = value.parse().expect("Invalid --query-paraphrases");
+        } else if key == "--eval-set" {
+            config.eval_set = value.to_string();
+        } else if key == "--eval-output" {
+            config.eval_output = Some(value.to_string());
+        } else if key == "--debug" {
+            config.debug = value.parse().expect("Invalid --debug");
+        } else if key == "--reranker-model-dir" {
+            config.reranker_model_dir = Some(value.to_string());
+        } else if key == "--auto-migrate" {
+            config.auto_migrate = value.parse().expect("Invalid --auto-migrate");
+        } else if key == "--vector-extension" {
+            config.vector.extension = parse_extension(value);
+        } else if key == "--vector-index" {
+            config.vector.index = parse_index(value);
+        } else if key == "--vector-ef-search" {
+            config.vector.ef_search = Some(value.parse().expect("Invalid --vector-ef-search"));
+        } else if key == "--vector-probes" {
+            config.vector.probes = Some(value.parse().expect("Invalid --vector-probes"));
+        } else if key == "--vector-lists" {
+            config.vector.lists = value.parse().expect("Invalid --vector-lists");
+        } else if key == "--embedding-backend" {
+            config.embedding.backend = parse_backend(value);
+        } else if key == "--embedding-model" {
+            config.embedding.model = value.to_string();
+        } else if key == "--embedding-dim" {
+            config.embedding.dimension = Some(value.parse().expect("Invalid --embedding-dim"));
+        } else if key == "--embedding-url" {
+            config.embedding.url = value.to_string();
+        } else if key == "--embedding-model-dir" {
+            config.embedding.model_dir = Some(value.to_string());
+        } else if key == "--embedding-cache" {
+            config.embedding.cache = value.parse().expect("Invalid --embedding-cache");
+        } else if key == "--embedding-batch-size" {
+            config.embedding.batch_size = value.parse().expect("Invalid --embedding-batch-size");
+        } else if key == "--embedding-queue-size" {
+            config.embedding.queue_size = value.parse().expect("Invalid --embedding-queue-size");
+        } else if key.is_empty() && !value.starts_with("--") {
+            config.command = match value {
+                "fetch-model" => Command::FetchModel,
+                "reembed" => Command::Reembed,
+                "migrate" => Command::Migrate,
+                "eval" => Command::Eval,
+                _ => panic!("Unknown command {value}"),
+            };
         }
     });
     if config.path.is_empty() {
-        if !path_from_env.is_empty() {
-            config.path = path_from_env;
-        } else {
-            panic!("--data-dir is required or set PATH in env");
-        }
+        config.path = path_from_env;
     }
-
     if config.index.is_empty() {
-        if !index_path.is_empty() {
-            config.index = index_path;
-        } else {
-            panic!("--index-path is required or set INDEX_PATH in env");
+        config.index = index_path;
+    }
+    config.collections = load_collections(&collections_file, &config);
+    if !config
+        .collections
+        .iter()
+        .any(|collection| collection.name == config.collection)
+    {
+        panic!(
+            "Unknown collection {}, set --collection or COLLECTION in env",
+            config.collection
+        );
+    }
+    if config.command == Command::Eval && config.eval_set.is_empty() {
+        panic!("--eval-set is required for eval");
+    }
+    // Evaluation ingests and retrieves like the server
+    if !matches!(config.command, Command::Serve | Command::Eval) {
+        return config;
+    }
+
+    let rerank = config.collections.iter().any(|c| c.retrieval.rerank);
+    if rerank && config.reranker_model_dir.is_none() {
+        eprintln!("Reranking is on but there is no --reranker-model-dir or RERANKER_MODEL_DIR, results keep the search order");
+    }
+
+    // SQLite keeps track of ingested files itself, the in-memory store ingests all of them anyway
+    let store_uri = env::var("PG_URI").unwrap_or_default();
+    let own_index = store_uri.starts_with("sqlite://") || store_uri.starts_with("memory://");
+    for collection in &config.collections {
+        if collection.path.is_empty() {
+            panic!(
+                "--data-dir is required or set PATH in env, or data_dir for {} in COLLECTIONS",
+                collection.name
+            );
+        }
+        if collection.index.is_empty() && !own_index {
+            panic!(
+                "--index-path is required or set INDEX_PATH in env, or index_path for {} in COLLECTIONS",
+                collection.name
+            );
         }
     }
 
     config
 }
 
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|v| v.trim().trim_start_matches('.').to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
 pub async fn start_repl(
-    tx: UnboundedSender<EncodingRequest>,
-    pool: Pool<Postgres>,
+    collections: Arc<HashMap<String, Collection>>,
+    mut collection: String,
+    store: Arc<dyn VectorStore>,
     req_client: reqwest::Client,
+    session: String,
+    reranker: Option<RerankSender>,
+    debug: bool,
 ) {
-    println!("Hi! Do you have any questions?");
-    let mut history = Vec::new();
+    let mut history = store.history(&session).await.unwrap_or_else(|e| {
+        eprintln!("Unable to load chat history, starting a new conversation: {e}");
+        vec![]
+    });
+    if history.is_empty() {
+        println!("Hi! Do you have any questions?");
+    } else {
+        println!("Welcome back! Picking up {session} where you left off.");
+    }
+    let mut filter = Filter::default();
     loop {
         let mut lock = io::stdout().lock();
         lock.write("> ".as_bytes()).unwrap();
@@ -77,27 +795,122 @@ pub async fn start_repl(
         drop(lock);
         let mut prompt = String::new();
         io::stdin().read_line(&mut prompt).unwrap();
-        let embeddings = generate_embedding_for_text(tx.clone(), prompt.clone())
-            .await
-            .expect("Something went wrong! Unable to generate embeddings.");
-        let segments = sqlx::query_as::<_, DocumentRef>(
-            "SELECT *, embedding <-> $1 as relevence FROM documents ORDER BY relevence LIMIT 4",
+        // `/collection` shows the collection questions are asked against, `/collection name`
+        // switches to another one
+        if let Some(name) = prompt.trim().strip_prefix("/collection") {
+            let name = name.trim();
+            if !name.is_empty() {
+                if collections.contains_key(name) {
+                    collection = name.to_string();
+                } else {
+                    eprintln!("Unknown collection {name}");
+                    continue;
+                }
+            }
+            let mut names: Vec<&str> = collections.keys().map(|name| name.as_str()).collect();
+            names.sort();
+            println!("Asking {collection} of {}", names.join(", "));
+            continue;
+        }
+        if let Some(command) = prompt.trim().strip_prefix("/filter") {
+            match filter_command(command.trim(), &mut filter) {
+                Ok(()) if filter.is_empty() => println!("Searching all documents"),
+                Ok(()) => println!("Filter: {}", serde_json::to_string(&filter).unwrap()),
+                Err(e) => eprintln!("{e}"),
+            }
+            continue;
+        }
+        let asked = &collections[&collection];
+        let question =
+            search_question(&req_client, &history, &prompt, &asked.retrieval, debug).await;
+        let retrieved = match retrieve(
+            store.as_ref(),
+            asked,
+            reranker.as_ref(),
+            &req_client,
+            &question,
+            &filter,
+            &asked.retrieval,
         )
-        .bind(&embeddings)
-        .fetch_all(&pool)
         .await
-        .unwrap();
-        let segments = sort_embeddings(segments);
-        let completion = Completion::new(segments.join("\n"), &req_client, history.clone());
-        let answer = completion
-            .generate(prompt.clone())
-            .await
-            .expect("Unable to generate message");
-        history.push(OpenAiCompletionMessage {
+        {
+            Ok(retrieved) => retrieved,
+            Err(e) => {
+                eprintln!("Something went wrong! Unable to find relevant segments: {e}");
+                continue;
+            }
+        };
+        let sources: Vec<Source> = retrieved.segments.iter().map(Source::from).collect();
+        let completion = Completion::new(retrieved.context, &req_client, history.clone());
+        let answer = match completion.generate(prompt.clone()).await {
+            Ok(answer) => answer,
+            Err(e) => {
+                eprintln!("Something went wrong! Unable to generate an answer: {e}");
+                continue;
+            }
+        };
+        let question = OpenAiCompletionMessage {
             role: crate::schemas::OpenAiCompletionRole::User,
             content: prompt,
-        });
+        };
+        for message in [&question, &answer] {
+            if let Err(e) = store.push_history(&session, message).await {
+                eprintln!("Unable to save chat history: {e}");
+            }
+        }
+        history.push(question);
         history.push(answer.clone());
         println!("{answer}", answer = answer.content.trim_start_matches("\n"));
+        for source in sources {
+            match (source.start_line, source.end_line) {
+                (Some(start), Some(end)) => println!("  - {}:{start}-{end}", source.doc_ref),
+                _ => println!("  - {} #{}", source.doc_ref, source.segment),
+            }
+        }
+    }
+}
+
+/// `/filter` shows the filter, `/filter clear` removes it, `/filter {...}` replaces it with a
+/// JSON filter and `/filter key=value ...` adds to it. `doc_ref`, `file_type` (comma separated),
+/// `after` and `before` (`YYYY-MM-DD`) are filter fields, any other key is matched in metadata.
+fn filter_command(command: &str, filter: &mut Filter) -> Result<(), String> {
+    if command.is_empty() {
+        return Ok(());
+    }
+    if command == "clear" {
+        *filter = Filter::default();
+        return Ok(());
+    }
+    if command.starts_with('{') {
+        *filter = serde_json::from_str(command).map_err(|e| format!("Invalid filter: {e}"))?;
+        return Ok(());
+    }
+    let mut updated = filter.clone();
+    for term in command.split_whitespace() {
+        let (key, value) = term
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid filter {term}, expected key=value"))?;
+        match key {
+            "doc_ref" => updated.doc_ref = Some(value.to_string()),
+            "file_type" => {
+                updated.file_type = Some(value.split(',').map(|t| t.to_string()).collect())
+            }
+            "after" => {
+                updated.modified_after =
+                    Some(parse_date(value).ok_or_else(|| format!("Invalid date {value}"))?)
+            }
+            "before" => {
+                updated.modified_before =
+                    Some(parse_date(value).ok_or_else(|| format!("Invalid date {value}"))?)
+            }
+            key => {
+                // Numbers and booleans keep their type, anything else is a string
+                let value = serde_json::from_str(value)
+                    .unwrap_or_else(|_| Value::String(value.to_string()));
+                updated.metadata.insert(key.to_string(), value);
+            }
+        }
     }
+    *filter = updated;
+    Ok(())
 }
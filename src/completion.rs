@@ -29,8 +29,6 @@ impl<'a> Completion<'a> {
         &self,
         prompt: String,
     ) -> Result<OpenAiCompletionMessage, Box<dyn Error>> {
-        const OPEN_AI_URI: &str = "https://api.openai.com/v1/chat/completions";
-        let token = std::env::var("OPEN_AI_TOKEN").unwrap();
         let mut messages = vec![
             OpenAiCompletionMessage {
                 role: OpenAiCompletionRole::System,
@@ -58,34 +56,160 @@ impl<'a> Completion<'a> {
             role: OpenAiCompletionRole::User,
             content: prompt,
         });
-        let response: Value = self
-            .client
-            .post(OPEN_AI_URI)
-            .header("Authorization", format!("Bearer {token}"))
-            .json(&OpenAiCompletionRequest {
-                model: OpenAIModel::GPT35TURBO,
-                messages,
-            })
-            .send()
-            .await?
-            .json()
-            .await?;
+        chat(self.client, messages).await
+    }
+}
+
+// Recent messages a follow-up question is read against
+const REWRITE_HISTORY: usize = 6;
+
+/// Rewrites the latest turn into a question that can be understood without the conversation, so
+/// follow-ups like "what about the second one?" retrieve the right segments.
+pub async fn condense_question(
+    client: &reqwest::Client,
+    history: &[OpenAiCompletionMessage],
+    question: &str,
+) -> Result<String, Box<dyn Error>> {
+    let recent = &history[history.len().saturating_sub(REWRITE_HISTORY)..];
+    let conversation: Vec<String> = recent
+        .iter()
+        .map(|message| {
+            let speaker = match message.role {
+                OpenAiCompletionRole::User => "User",
+                _ => "Assistant",
+            };
+            format!("{speaker}: {}", message.content.trim())
+        })
+        .collect();
+    let messages = vec![
+        OpenAiCompletionMessage {
+            role: OpenAiCompletionRole::System,
+            content: "Rewrite the user's latest message as a standalone question that can be understood without the conversation. Reply with the question only.".to_string(),
+        },
+        OpenAiCompletionMessage {
+            role: OpenAiCompletionRole::User,
+            content: format!(
+                "Conversation:\n{}\n\nLatest message: {}",
+                conversation.join("\n"),
+                question.trim()
+            ),
+        },
+    ];
+    Ok(chat(client, messages).await?.content.trim().to_string())
+}
+
+/// Asks for `count` rewordings of a question, to search with alongside the original.
+pub async fn paraphrase_question(
+    client: &reqwest::Client,
+    question: &str,
+    count: usize,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let messages = vec![
+        OpenAiCompletionMessage {
+            role: OpenAiCompletionRole::System,
+            content: format!(
+                "Write {count} different rephrasings of the user's question, using the wording documentation would use. Reply with one per line and nothing else."
+            ),
+        },
+        OpenAiCompletionMessage {
+            role: OpenAiCompletionRole::User,
+            content: question.trim().to_string(),
+        },
+    ];
+    Ok(chat(client, messages)
+        .await?
+        .content
+        .lines()
+        .map(|line| strip_list_marker(line).to_string())
+        .filter(|line| !line.is_empty())
+        .take(count)
+        .collect())
+}
+
+// Numbering or a bullet in front of a line, like `1.`, `2)` or `-`. Only taken off when followed
+// by whitespace, so a line that starts with a number keeps it.
+fn strip_list_marker(line: &str) -> &str {
+    let line = line.trim();
+    let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let marker = if digits > 0 && line[digits..].starts_with(['.', ')']) {
+        digits + 1
+    } else if line.starts_with(['-', '*']) {
+        1
+    } else {
+        0
+    };
+    match line[marker..].strip_prefix(char::is_whitespace) {
+        Some(rest) if marker > 0 => rest.trim_start(),
+        _ => line,
+    }
+}
+
+/// Asks for a passage that would answer the question (HyDE), which tends to land closer to the
+/// documents than the question itself.
+pub async fn hypothetical_answer(
+    client: &reqwest::Client,
+    question: &str,
+) -> Result<String, Box<dyn Error>> {
+    let messages = vec![
+        OpenAiCompletionMessage {
+            role: OpenAiCompletionRole::System,
+            content: "Write a short passage from documentation that answers the user's question. Reply with the passage only.".to_string(),
+        },
+        OpenAiCompletionMessage {
+            role: OpenAiCompletionRole::User,
+            content: question.trim().to_string(),
+        },
+    ];
+    Ok(chat(client, messages).await?.content.trim().to_string())
+}
+
+async fn chat(
+    client: &reqwest::Client,
+    messages: Vec<OpenAiCompletionMessage>,
+) -> Result<OpenAiCompletionMessage, Box<dyn Error>> {
+    const OPEN_AI_URI: &str = "https://api.openai.com/v1/chat/completions";
+    let token = std::env::var("OPEN_AI_TOKEN").map_err(|_| "OPEN_AI_TOKEN is not set")?;
+    let response: Value = client
+        .post(OPEN_AI_URI)
+        .header("Authorization", format!("Bearer {token}"))
+        .json(&OpenAiCompletionRequest {
+            model: OpenAIModel::GPT35TURBO,
+            messages,
+        })
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    // Rate limits, bad keys and oversized prompts come back as `{"error": {"message": ...}}`
+    if let Some(error) = response.get("error") {
+        let message = error
+            .get("message")
+            .and_then(|m| m.as_str())
+            .unwrap_or("unknown error");
+        return Err(format!("Completion failed: {message}").into());
+    }
+    let message = response
+        .pointer("/choices/0/message")
+        .ok_or("Completion response has no message")?;
+    Ok(from_value(message.clone())?)
+}
 
-        // TODO: Error handling
-        let completion: OpenAiCompletionMessage = from_value(
-            response
-                .get("choices")
-                .unwrap()
-                .to_owned()
-                .as_array()
-                .unwrap()
-                .get(0)
-                .unwrap()
-                .get("message")
-                .unwrap()
-                .clone(),
-        )?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(completion)
+    #[test]
+    fn list_markers_are_stripped_but_leading_numbers_kept() {
+        assert_eq!(
+            strip_list_marker("1. How do I reset it?"),
+            "How do I reset it?"
+        );
+        assert_eq!(strip_list_marker(" 12) Resetting"), "Resetting");
+        assert_eq!(strip_list_marker("- Reset steps"), "Reset steps");
+        assert_eq!(strip_list_marker("* Reset steps"), "Reset steps");
+        assert_eq!(strip_list_marker("2FA setup"), "2FA setup");
+        assert_eq!(strip_list_marker("3.5 release notes"), "3.5 release notes");
+        assert_eq!(strip_list_marker("2. 2FA setup"), "2FA setup");
     }
 }
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    cli::{DedupConfig, DedupMode},
+    schemas::DocumentRef,
+    store::{with_duplicate, StoreError, VectorStore},
+};
+
+// Words per shingle, near-duplicates share most of their word triples
+const SHINGLE: usize = 3;
+// Segments shorter than this, e.g. headings, are too common to be boilerplate and always kept
+const MIN_WORDS: usize = 8;
+
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+// FNV-1a, fingerprints are stored so the hash has to be stable across builds
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// 64 bit SimHash over the word shingles of `text`, texts that differ in a few words differ in a
+/// few bits.
+pub fn simhash(text: &str) -> u64 {
+    let words = words(text);
+    if words.is_empty() {
+        return 0;
+    }
+    let mut weights = [0i32; 64];
+    for shingle in words.windows(SHINGLE.min(words.len())) {
+        let hash = fnv1a(&shingle.join(" "));
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if (hash >> bit) & 1 == 1 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+    weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .fold(0, |hash, (bit, _)| hash | (1 << bit))
+}
+
+/// Fingerprint of a stored segment, computed from `raw` for segments stored before they were kept.
+pub fn fingerprint(stored: Option<i64>, raw: Option<&str>) -> u64 {
+    stored
+        .map(|hash| hash as u64)
+        .unwrap_or_else(|| simhash(raw.unwrap_or_default()))
+}
+
+/// Fingerprints of a collection's segments, looked up by splitting them into one more band than
+/// the bits two near-duplicates may differ in, so at least one band of a match is identical.
+pub struct Deduplicator {
+    distance: u32,
+    bands: usize,
+    segments: Vec<Option<(String, i64, u64)>>,
+    by_band: HashMap<(usize, u64), Vec<usize>>,
+    by_doc: HashMap<String, Vec<usize>>,
+}
+
+impl Deduplicator {
+    pub fn new(distance: u32) -> Self {
+        Deduplicator {
+            distance,
+            bands: (distance as usize + 1).min(64),
+            segments: Vec::new(),
+            by_band: HashMap::new(),
+            by_doc: HashMap::new(),
+        }
+    }
+
+    /// Starts out with every segment the collection already has.
+    pub async fn load(
+        store: &dyn VectorStore,
+        collection: &str,
+        distance: u32,
+    ) -> Result<Self, StoreError> {
+        let mut deduplicator = Deduplicator::new(distance);
+        for (doc_ref, segment, hash) in store.fingerprints(collection).await? {
+            deduplicator.insert(&doc_ref, segment, hash);
+        }
+        Ok(deduplicator)
+    }
+
+    fn band(&self, hash: u64, band: usize) -> u64 {
+        let width = 64 / self.bands;
+        let mask = if width == 64 {
+            u64::MAX
+        } else {
+            (1 << width) - 1
+        };
+        (hash >> (band * width)) & mask
+    }
+
+    pub fn insert(&mut self, doc_ref: &str, segment: i64, hash: u64) {
+        let id = self.segments.len();
+        self.segments
+            .push(Some((doc_ref.to_string(), segment, hash)));
+        for band in 0..self.bands {
+            let key = (band, self.band(hash, band));
+            self.by_band.entry(key).or_default().push(id);
+        }
+        self.by_doc.entry(doc_ref.to_string()).or_default().push(id);
+    }
+
+    /// Forgets the segments of a document, returning their numbers.
+    pub fn remove_doc(&mut self, doc_ref: &str) -> Vec<i64> {
+        let ids = self.by_doc.remove(doc_ref).unwrap_or_default();
+        ids.into_iter()
+            .filter_map(|id| self.segments[id].take())
+            .map(|(_, segment, _)| segment)
+            .collect()
+    }
+
+    /// A segment `text` is a near-duplicate of, as its `doc_ref` and segment number.
+    pub fn find(&self, text: &str, hash: u64) -> Option<(String, i64)> {
+        if words(text).len() < MIN_WORDS {
+            return None;
+        }
+        (0..self.bands)
+            .filter_map(|band| self.by_band.get(&(band, self.band(hash, band))))
+            .flatten()
+            .filter_map(|id| self.segments[*id].as_ref())
+            .find(|(_, _, other)| (hash ^ other).count_ones() <= self.distance)
+            .map(|(doc_ref, segment, _)| (doc_ref.clone(), *segment))
+    }
+}
+
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct IngestStats {
+    pub files: usize,
+    pub segments: usize,
+    pub duplicates: usize,
+}
+
+// Where a merged duplicate came from, kept in the `duplicates` metadata of the segment it repeats
+fn reference(doc: &DocumentRef) -> Value {
+    json!({
+        "doc_ref": doc.doc_ref,
+        "segment": doc.segment,
+        "start_offset": doc.start_offset,
+        "end_offset": doc.end_offset,
+        "start_line": doc.start_line,
+        "end_line": doc.end_line,
+    })
+}
+
+/// Documents that have a reference merged into one of `segments`.
+pub fn referenced_docs(segments: &[DocumentRef]) -> Vec<String> {
+    segments
+        .iter()
+        .filter_map(|doc| doc.metadata.get("duplicates"))
+        .filter_map(|duplicates| duplicates.as_array())
+        .flatten()
+        .filter_map(|duplicate| duplicate.get("doc_ref"))
+        .filter_map(|doc_ref| doc_ref.as_str())
+        .map(|doc_ref| doc_ref.to_string())
+        .collect()
+}
+
+/// Drops the segments of a document that are near-duplicates of ones already stored or earlier
+/// in the document. When merging, a reference to each is added to the segment it repeats.
+pub async fn dedupe(
+    store: &dyn VectorStore,
+    collection: &str,
+    documents: Vec<DocumentRef>,
+    seen: &mut Deduplicator,
+    config: &DedupConfig,
+    stats: &mut IngestStats,
+) -> Result<Vec<DocumentRef>, StoreError> {
+    let mut kept: Vec<DocumentRef> = Vec::with_capacity(documents.len());
+    for doc in documents {
+        let hash = simhash(&doc.raw);
+        let Some((doc_ref, segment)) = seen.find(&doc.raw, hash) else {
+            seen.insert(&doc.doc_ref, doc.segment, hash);
+            kept.push(doc);
+            continue;
+        };
+        stats.duplicates += 1;
+        if config.mode != DedupMode::Merge {
+            continue;
+        }
+        // Earlier segments of this document are not stored yet
+        match kept
+            .iter_mut()
+            .find(|k| k.doc_ref == doc_ref && k.segment == segment)
+        {
+            Some(original) => {
+                original.metadata = with_duplicate(&original.metadata, reference(&doc))
+            }
+            None => {
+                store
+                    .add_duplicate(collection, &doc_ref, segment, reference(&doc))
+                    .await?
+            }
+        }
+    }
+    Ok(kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+
+    const CLAIMS: &str =
+        "The company reimburses travel expenses within thirty days of receiving a \
+                          complete claim with receipts attached";
+    const PRINTERS: &str = "Printers on the third floor need a badge before they release queued \
+                            print jobs to anyone";
+
+    fn segment(doc_ref: &str, segment: i64, raw: &str) -> DocumentRef {
+        DocumentRef {
+            collection: "test_dedup".to_string(),
+            raw: raw.to_string(),
+            doc_ref: doc_ref.to_string(),
+            segment,
+            metadata: json!({}),
+            ..DocumentRef::default()
+        }
+    }
+
+    #[test]
+    fn simhash_ignores_case_and_punctuation() {
+        assert_eq!(simhash(CLAIMS), simhash(&CLAIMS.to_uppercase()));
+        assert_eq!(simhash(CLAIMS), simhash(&CLAIMS.replace(' ', ", ")));
+        assert_eq!(simhash(""), 0);
+        assert_eq!(fingerprint(Some(-1), Some(CLAIMS)), u64::MAX);
+        assert_eq!(fingerprint(None, Some(CLAIMS)), simhash(CLAIMS));
+    }
+
+    #[test]
+    fn simhash_is_closer_for_near_duplicates() {
+        let near = simhash(&CLAIMS.replace("attached", "included"));
+        let near = (simhash(CLAIMS) ^ near).count_ones();
+        let different = (simhash(CLAIMS) ^ simhash(PRINTERS)).count_ones();
+        assert!(near < different, "{near} >= {different}");
+    }
+
+    #[test]
+    fn find_matches_within_the_distance() {
+        let mut seen = Deduplicator::new(3);
+        let hash = simhash(CLAIMS);
+        seen.insert("a.md", 2, hash);
+        assert_eq!(seen.find(CLAIMS, hash), Some(("a.md".to_string(), 2)));
+        // Differences spread over every band
+        assert_eq!(
+            seen.find(CLAIMS, hash ^ (1 << 0 | 1 << 20 | 1 << 63)),
+            Some(("a.md".to_string(), 2))
+        );
+        assert_eq!(seen.find(CLAIMS, hash ^ 0b1111), None);
+        assert_eq!(seen.find(CLAIMS, !hash), None);
+    }
+
+    #[test]
+    fn find_keeps_short_segments() {
+        let mut seen = Deduplicator::new(3);
+        seen.insert("a.md", 0, simhash("Contents"));
+        assert_eq!(seen.find("Contents", simhash("Contents")), None);
+    }
+
+    #[test]
+    fn remove_doc_forgets_its_segments() {
+        let mut seen = Deduplicator::new(3);
+        seen.insert("a.md", 0, simhash(CLAIMS));
+        seen.insert("a.md", 1, simhash(PRINTERS));
+        seen.insert("b.md", 0, simhash(PRINTERS));
+        assert_eq!(seen.remove_doc("a.md"), vec![0, 1]);
+        assert_eq!(seen.remove_doc("a.md"), Vec::<i64>::new());
+        assert_eq!(seen.find(CLAIMS, simhash(CLAIMS)), None);
+        assert_eq!(
+            seen.find(PRINTERS, simhash(PRINTERS)),
+            Some(("b.md".to_string(), 0))
+        );
+    }
+
+    #[tokio::test]
+    async fn dedupe_merges_references_into_what_they_repeat() {
+        let store = MemoryStore::default();
+        store
+            .upsert(vec![segment("old.md", 0, CLAIMS)])
+            .await
+            .unwrap();
+        let mut seen = Deduplicator::load(&store, "test_dedup", 3).await.unwrap();
+        let config = DedupConfig {
+            mode: DedupMode::Merge,
+            ..DedupConfig::default()
+        };
+        let mut stats = IngestStats::default();
+        let kept = dedupe(
+            &store,
+            "test_dedup",
+            vec![
+                segment("new.md", 0, &CLAIMS.to_uppercase()),
+                segment("new.md", 1, PRINTERS),
+                segment("new.md", 2, PRINTERS),
+                segment("new.md", 3, "Contents"),
+                segment("new.md", 4, "Contents"),
+            ],
+            &mut seen,
+            &config,
+            &mut stats,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            kept.iter().map(|doc| doc.segment).collect::<Vec<_>>(),
+            vec![1, 3, 4]
+        );
+        assert_eq!(stats.duplicates, 2);
+        assert_eq!(referenced_docs(&kept), vec!["new.md"]);
+        assert_eq!(kept[0].metadata["duplicates"][0]["segment"], 2);
+        let old = store.segments("test_dedup", "old.md", &[0]).await.unwrap();
+        assert_eq!(referenced_docs(&old), vec!["new.md"]);
+        assert_eq!(old[0].metadata["duplicates"][0]["segment"], 0);
+    }
+
+    #[tokio::test]
+    async fn dedupe_skips_without_references() {
+        let store = MemoryStore::default();
+        let mut seen = Deduplicator::new(3);
+        let config = DedupConfig {
+            mode: DedupMode::Skip,
+            ..DedupConfig::default()
+        };
+        let mut stats = IngestStats::default();
+        let kept = dedupe(
+            &store,
+            "test_dedup",
+            vec![segment("a.md", 0, PRINTERS), segment("a.md", 1, PRINTERS)],
+            &mut seen,
+            &config,
+            &mut stats,
+        )
+        .await
+        .unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(stats.duplicates, 1);
+        assert!(referenced_docs(&kept).is_empty());
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use reqwest::Client;
+use rust_bert::pipelines::sentence_embeddings::{
+    SentenceEmbeddingsBuilder, SentenceEmbeddingsModel, SentenceEmbeddingsModelType,
+};
+use serde_json::{json, Value};
+use tokio::runtime::Handle;
+
+use crate::cli::{EmbeddingBackend, EmbeddingConfig};
+
+/// Turns texts into vectors, implementations run on the blocking embedding worker.
+pub trait Embedder {
+    fn id(&self) -> &str;
+    fn dimension(&self) -> usize;
+    fn embed(&self, raw: &[&str]) -> Result<Vec<Vec<f32>>, Box<dyn Error>>;
+}
+
+pub fn create_embedder(
+    config: &EmbeddingConfig,
+    handle: &Handle,
+) -> Result<Box<dyn Embedder>, Box<dyn Error>> {
+    let dimension = model_dimension(config)?;
+    match config.backend {
+        EmbeddingBackend::RustBert => Ok(Box::new(RustBertEmbedder::new(config, dimension)?)),
+        EmbeddingBackend::OpenAi => Ok(Box::new(OpenAiEmbedder::new(
+            config,
+            dimension,
+            handle.clone(),
+        ))),
+    }
+}
+
+/// Dimension of the vectors the configured model produces, known without loading it.
+pub fn model_dimension(config: &EmbeddingConfig) -> Result<usize, Box<dyn Error>> {
+    let known = match config.backend {
+        EmbeddingBackend::RustBert => Some(rust_bert_model(&config.model)?.1),
+        EmbeddingBackend::OpenAi => match config.model.as_str() {
+            "text-embedding-ada-002" | "text-embedding-3-small" => Some(1536),
+            "text-embedding-3-large" => Some(3072),
+            _ => None,
+        },
+    };
+    match (known, config.dimension) {
+        (Some(known), Some(dimension))
+            if known != dimension && config.backend == EmbeddingBackend::RustBert =>
+        {
+            Err(format!(
+                "{} produces {known} dimensions, not {dimension}",
+                config.model
+            )
+            .into())
+        }
+        (_, Some(dimension)) => Ok(dimension),
+        (Some(known), None) => Ok(known),
+        (None, None) => Err(format!("EMBEDDING_DIM is required for {}", config.model).into()),
+    }
+}
+
+fn rust_bert_model(model: &str) -> Result<(SentenceEmbeddingsModelType, usize), Box<dyn Error>> {
+    let model = model.trim_start_matches("sentence-transformers/");
+    Ok(match model {
+        "all-MiniLM-L6-v2" => (SentenceEmbeddingsModelType::AllMiniLmL6V2, 384),
+        "all-MiniLM-L12-v2" => (SentenceEmbeddingsModelType::AllMiniLmL12V2, 384),
+        "all-distilroberta-v1" => (SentenceEmbeddingsModelType::AllDistilrobertaV1, 768),
+        "distiluse-base-multilingual-cased" => (
+            SentenceEmbeddingsModelType::DistiluseBaseMultilingualCased,
+            512,
+        ),
+        "bert-base-nli-mean-tokens" => (SentenceEmbeddingsModelType::BertBaseNliMeanTokens, 768),
+        "paraphrase-albert-small-v2" => (SentenceEmbeddingsModelType::ParaphraseAlbertSmallV2, 768),
+        "sentence-t5-base" => (SentenceEmbeddingsModelType::SentenceT5Base, 768),
+        _ => return Err(format!("Unknown rust-bert sentence embeddings model {model}").into()),
+    })
+}
+
+// Files every sentence-transformers model directory needs, the rest depend on their contents
+const MODEL_FILES: [&str; 4] = [
+    "modules.json",
+    "config.json",
+    "sentence_bert_config.json",
+    "rust_model.ot",
+];
+// Fetched when the model has them
+const OPTIONAL_MODEL_FILES: [&str; 2] = ["tokenizer_config.json", "special_tokens_map.json"];
+
+fn required_model_files(dir: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut files: Vec<String> = MODEL_FILES.iter().map(|f| f.to_string()).collect();
+    if let Ok(modules) = fs::read_to_string(dir.join("modules.json")) {
+        let modules: Vec<Value> = serde_json::from_str(&modules)?;
+        for module in modules {
+            let path = module.get("path").and_then(|p| p.as_str()).unwrap_or("");
+            let kind = module.get("type").and_then(|t| t.as_str()).unwrap_or("");
+            if kind.ends_with("Pooling") {
+                files.push(format!("{path}/config.json"));
+            } else if kind.ends_with("Dense") {
+                files.push(format!("{path}/config.json"));
+                files.push(format!("{path}/rust_model.ot"));
+            }
+        }
+    }
+    if let Ok(transformer) = fs::read_to_string(dir.join("config.json")) {
+        let transformer: Value = serde_json::from_str(&transformer)?;
+        match transformer.get("model_type").and_then(|t| t.as_str()) {
+            Some("roberta") => files.extend(["vocab.json".to_string(), "merges.txt".to_string()]),
+            Some("albert") | Some("t5") => files.push("spiece.model".to_string()),
+            _ => files.push("vocab.txt".to_string()),
+        }
+    }
+    Ok(files)
+}
+
+/// Makes sure a configured model directory has everything needed to load the model, so a missing
+/// file fails start-up instead of the embedding worker.
+pub fn check_model_dir(config: &EmbeddingConfig) -> Result<(), Box<dyn Error>> {
+    let dir = match (&config.backend, &config.model_dir) {
+        (EmbeddingBackend::RustBert, Some(dir)) => Path::new(dir),
+        _ => return Ok(()),
+    };
+    let missing: Vec<String> = required_model_files(dir)?
+        .into_iter()
+        .filter(|file| !dir.join(file).is_file())
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "{dir:?} is missing {}, run `llm-chatbot fetch-model --embedding-model-dir={}` on a connected machine",
+            missing.join(", "),
+            dir.display()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Downloads a rust-bert model from Hugging Face into the model directory and checks that it
+/// loads and produces vectors of the expected size.
+pub async fn fetch_model(config: &EmbeddingConfig) -> Result<(), Box<dyn Error>> {
+    if config.backend != EmbeddingBackend::RustBert {
+        return Err("Only rust-bert models can be fetched".into());
+    }
+    let dir = PathBuf::from(
+        config
+            .model_dir
+            .as_ref()
+            .ok_or("EMBEDDING_MODEL_DIR or --embedding-model-dir is required")?,
+    );
+    let (_, dimension) = rust_bert_model(&config.model)?;
+    let repo = format!(
+        "https://huggingface.co/sentence-transformers/{}/resolve/main",
+        config.model.trim_start_matches("sentence-transformers/")
+    );
+    let client = Client::new();
+    for file in MODEL_FILES {
+        download(&client, &repo, &dir, file, true).await?;
+    }
+    for file in OPTIONAL_MODEL_FILES {
+        download(&client, &repo, &dir, file, false).await?;
+    }
+    // Module and tokenizer files are only known once modules.json and config.json are there
+    for file in required_model_files(&dir)? {
+        if !dir.join(&file).is_file() {
+            download(&client, &repo, &dir, &file, true).await?;
+        }
+    }
+    check_model_dir(config)?;
+
+    let model_dir = dir.clone();
+    let embedding = tokio::task::spawn_blocking(move || -> Result<Vec<Vec<f32>>, String> {
+        let model = SentenceEmbeddingsBuilder::local(model_dir)
+            .create_model()
+            .map_err(|e| e.to_string())?;
+        model.encode(&["verify"]).map_err(|e| e.to_string())
+    })
+    .await??;
+    if embedding[0].len() != dimension {
+        return Err(format!(
+            "{} produced {} dimensions, expected {dimension}",
+            config.model,
+            embedding[0].len()
+        )
+        .into());
+    }
+    println!("{} is ready in {}", config.model, dir.display());
+    Ok(())
+}
+
+async fn download(
+    client: &Client,
+    repo: &str,
+    dir: &Path,
+    file: &str,
+    required: bool,
+) -> Result<(), Box<dyn Error>> {
+    let response = client.get(format!("{repo}/{file}")).send().await?;
+    if !required && response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(());
+    }
+    let bytes = response.error_for_status()?.bytes().await?;
+    let path = dir.join(file);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Written under a temporary name first so an interrupted download is never mistaken for a file
+    let partial = path.with_extension("partial");
+    fs::write(&partial, &bytes)?;
+    fs::rename(&partial, &path)?;
+    println!("Fetched {file} ({} bytes)", bytes.len());
+    Ok(())
+}
+
+pub struct RustBertEmbedder {
+    id: String,
+    dimension: usize,
+    model: SentenceEmbeddingsModel,
+}
+
+impl RustBertEmbedder {
+    fn new(config: &EmbeddingConfig, dimension: usize) -> Result<Self, Box<dyn Error>> {
+        let (model_type, _) = rust_bert_model(&config.model)?;
+        let model = match &config.model_dir {
+            Some(dir) => SentenceEmbeddingsBuilder::local(dir).create_model()?,
+            None => SentenceEmbeddingsBuilder::remote(model_type).create_model()?,
+        };
+        Ok(RustBertEmbedder {
+            id: config.model.clone(),
+            dimension,
+            model,
+        })
+    }
+}
+
+impl Embedder for RustBertEmbedder {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn embed(&self, raw: &[&str]) -> Result<Vec<Vec<f32>>, Box<dyn Error>> {
+        Ok(self.model.encode(raw)?)
+    }
+}
+
+/// Any server implementing OpenAI's `/v1/embeddings`, including local stand-ins.
+pub struct OpenAiEmbedder {
+    id: String,
+    dimension: usize,
+    // Only sent when configured, models with a fixed size reject it
+    requested_dimension: Option<usize>,
+    url: String,
+    token: String,
+    client: Client,
+    handle: Handle,
+}
+
+impl OpenAiEmbedder {
+    fn new(config: &EmbeddingConfig, dimension: usize, handle: Handle) -> Self {
+        OpenAiEmbedder {
+            id: config.model.clone(),
+            dimension,
+            requested_dimension: config.dimension,
+            url: config.url.clone(),
+            token: std::env::var("EMBEDDING_API_KEY")
+                .or_else(|_| std::env::var("OPEN_AI_TOKEN"))
+                .unwrap_or_default(),
+            client: Client::new(),
+            handle,
+        }
+    }
+}
+
+impl Embedder for OpenAiEmbedder {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn embed(&self, raw: &[&str]) -> Result<Vec<Vec<f32>>, Box<dyn Error>> {
+        let mut body = json!({ "model": self.id, "input": raw });
+        if let Some(dimension) = self.requested_dimension {
+            body["dimensions"] = Value::from(dimension);
+        }
+        let response: Value = self.handle.block_on(async {
+            self.client
+                .post(&self.url)
+                .header("Authorization", format!("Bearer {}", self.token))
+                .json(&body)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+        })?;
+        let data = response
+            .get("data")
+            .and_then(|data| data.as_array())
+            .ok_or("Embeddings response has no data")?;
+        let mut embeddings = vec![Vec::new(); raw.len()];
+        for (i, item) in data.iter().enumerate() {
+            let index = item
+                .get("index")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(i as u64) as usize;
+            let embedding: Vec<f32> = serde_json::from_value(
+                item.get("embedding")
+                    .ok_or("Embeddings response item has no embedding")?
+                    .clone(),
+            )?;
+            if embedding.len() != self.dimension {
+                return Err(format!(
+                    "{} returned {} dimensions, expected {}",
+                    self.id,
+                    embedding.len(),
+                    self.dimension
+                )
+                .into());
+            }
+            *embeddings
+                .get_mut(index)
+                .ok_or("Embeddings response index out of range")? = embedding;
+        }
+        if embeddings.iter().any(|embedding| embedding.is_empty()) {
+            return Err("Embeddings response is missing inputs".into());
+        }
+        Ok(embeddings)
+    }
+}
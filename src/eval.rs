@@ -0,0 +1,366 @@
+use std::{
+    error::Error,
+    fs,
+    io::{BufRead, BufReader, BufWriter},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::{QueryStrategy, RetrievalConfig},
+    retrieval::retrieve,
+    schemas::AppState,
+    store::Filter,
+};
+
+/// A line of the evaluation set: a question and the segments that answer it.
+#[derive(Deserialize, Debug)]
+struct Case {
+    #[serde(default)]
+    id: Option<String>,
+    question: String,
+    expected: Vec<Expected>,
+    // Defaults to the collection asked by default
+    #[serde(default)]
+    collection: Option<String>,
+    #[serde(default)]
+    filter: Filter,
+}
+
+/// A segment that answers a question, or any segment of the document without `segment`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Expected {
+    doc_ref: String,
+    #[serde(default)]
+    segment: Option<i64>,
+}
+
+impl Expected {
+    // Paths may be given relative to the data directory, e.g. `handbook.pdf` for `data/handbook.pdf`
+    fn matches(&self, doc_ref: &str, segment: i64) -> bool {
+        let path = doc_ref == self.doc_ref
+            || doc_ref
+                .strip_suffix(self.doc_ref.as_str())
+                .is_some_and(|prefix| prefix.ends_with('/'));
+        path && self.segment.is_none_or(|s| s == segment)
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Retrieved {
+    doc_ref: String,
+    segment: i64,
+    relevence: Option<f32>,
+}
+
+#[derive(Serialize, Debug)]
+struct CaseResult {
+    id: Option<String>,
+    question: String,
+    collection: String,
+    expected: Vec<Expected>,
+    retrieved: Vec<Retrieved>,
+    recall: f64,
+    reciprocal_rank: f64,
+    ndcg: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Retrieval settings a collection was scored with, to tell reports apart.
+#[derive(Serialize, Debug)]
+struct Settings {
+    embedding_model: String,
+    top_k: usize,
+    min_similarity: Option<f32>,
+    context_budget: Option<usize>,
+    hybrid: bool,
+    rerank: bool,
+    mmr: bool,
+    strategy: QueryStrategy,
+}
+
+impl Settings {
+    fn new(model: &str, config: &RetrievalConfig) -> Self {
+        Settings {
+            embedding_model: model.to_string(),
+            top_k: config.top_k,
+            min_similarity: config.min_similarity,
+            context_budget: config.context_budget,
+            hybrid: config.hybrid,
+            rerank: config.rerank,
+            mmr: config.mmr,
+            strategy: config.strategy,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Summary {
+    collection: String,
+    questions: usize,
+    failed: usize,
+    k: usize,
+    recall: f64,
+    mrr: f64,
+    ndcg: f64,
+    settings: Settings,
+}
+
+#[derive(Serialize, Debug)]
+struct Report {
+    eval_set: String,
+    timestamp: u64,
+    summaries: Vec<Summary>,
+    questions: Vec<CaseResult>,
+}
+
+// Recall, reciprocal rank and nDCG of the first `k` retrieved segments with binary relevance.
+// A retrieved segment credits at most one expected segment, so one document expected as a whole
+// is found once however many of its segments are retrieved.
+fn score(retrieved: &[Retrieved], expected: &[Expected], k: usize) -> (f64, f64, f64) {
+    let mut found = vec![false; expected.len()];
+    let mut first = None;
+    let mut dcg = 0f64;
+    for (rank, doc) in retrieved.iter().take(k).enumerate() {
+        let matching: Vec<usize> = (0..expected.len())
+            .filter(|i| expected[*i].matches(&doc.doc_ref, doc.segment))
+            .collect();
+        if matching.is_empty() {
+            continue;
+        }
+        first.get_or_insert(rank);
+        if let Some(i) = matching.into_iter().find(|i| !found[*i]) {
+            found[i] = true;
+            dcg += 1f64 / ((rank + 2) as f64).log2();
+        }
+    }
+    let ideal: f64 = (0..expected.len().min(k))
+        .map(|rank| 1f64 / ((rank + 2) as f64).log2())
+        .sum();
+    let recall = found.iter().filter(|f| **f).count() as f64 / expected.len() as f64;
+    let reciprocal_rank = first.map_or(0f64, |rank| 1f64 / (rank + 1) as f64);
+    (recall, reciprocal_rank, dcg / ideal)
+}
+
+fn read_cases(path: &str) -> Result<Vec<Case>, Box<dyn Error>> {
+    let mut cases = Vec::new();
+    for (number, line) in BufReader::new(fs::File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let case: Case = serde_json::from_str(&line)
+            .map_err(|e| format!("Invalid question on line {} of {path}: {e}", number + 1))?;
+        if case.expected.is_empty() {
+            return Err(
+                format!("Question on line {} of {path} expects nothing", number + 1).into(),
+            );
+        }
+        cases.push(case);
+    }
+    Ok(cases)
+}
+
+/// Asks every question of a JSONL evaluation set through the retrieval path the server uses,
+/// with each collection's settings, and reports recall@k, MRR and nDCG@k per collection as a
+/// table, and with every question in JSON to `output`.
+pub async fn evaluate(
+    state: &AppState,
+    path: &str,
+    output: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let cases = read_cases(path)?;
+    let mut results: Vec<CaseResult> = Vec::with_capacity(cases.len());
+    for case in cases {
+        let collection = case.collection.as_deref();
+        let asked = state
+            .collection(collection)
+            .ok_or_else(|| format!("Unknown collection {}", collection.unwrap_or_default()))?;
+        let k = asked.retrieval.top_k;
+        let (retrieved, error) = match retrieve(
+            state.store.as_ref(),
+            asked,
+            state.reranker.as_ref(),
+            &state.req_client,
+            &case.question,
+            &case.filter,
+            &asked.retrieval,
+        )
+        .await
+        {
+            Ok(found) => (
+                found
+                    .segments
+                    .into_iter()
+                    .map(|doc| Retrieved {
+                        doc_ref: doc.doc_ref,
+                        segment: doc.segment,
+                        relevence: doc.relevence,
+                    })
+                    .collect(),
+                None,
+            ),
+            Err(e) => {
+                eprintln!("Failed to retrieve for {:?}: {e}", case.question);
+                (Vec::new(), Some(e.to_string()))
+            }
+        };
+        let (recall, reciprocal_rank, ndcg) = score(&retrieved, &case.expected, k);
+        if state.debug {
+            println!(
+                "{recall:.3} {reciprocal_rank:.3} {ndcg:.3} {}",
+                case.question
+            );
+        }
+        results.push(CaseResult {
+            id: case.id,
+            question: case.question,
+            collection: asked.name.clone(),
+            expected: case.expected,
+            retrieved,
+            recall,
+            reciprocal_rank,
+            ndcg,
+            error,
+        });
+    }
+
+    let mut names: Vec<&String> = results.iter().map(|r| &r.collection).collect();
+    names.sort();
+    names.dedup();
+    let summaries: Vec<Summary> = names
+        .into_iter()
+        .map(|name| {
+            let collection = &state.collections[name];
+            let asked: Vec<&CaseResult> =
+                results.iter().filter(|r| &r.collection == name).collect();
+            let mean = |metric: fn(&CaseResult) -> f64| {
+                asked.iter().copied().map(metric).sum::<f64>() / asked.len() as f64
+            };
+            Summary {
+                collection: name.clone(),
+                questions: asked.len(),
+                failed: asked.iter().filter(|r| r.error.is_some()).count(),
+                k: collection.retrieval.top_k,
+                recall: mean(|r| r.recall),
+                mrr: mean(|r| r.reciprocal_rank),
+                ndcg: mean(|r| r.ndcg),
+                settings: Settings::new(&collection.tx.model, &collection.retrieval),
+            }
+        })
+        .collect();
+
+    println!(
+        "{:<20} {:>9} {:>6} {:>4} {:>9} {:>7} {:>7}",
+        "collection", "questions", "failed", "k", "recall@k", "MRR", "nDCG@k"
+    );
+    for summary in &summaries {
+        println!(
+            "{:<20} {:>9} {:>6} {:>4} {:>9.3} {:>7.3} {:>7.3}",
+            summary.collection,
+            summary.questions,
+            summary.failed,
+            summary.k,
+            summary.recall,
+            summary.mrr,
+            summary.ndcg
+        );
+    }
+
+    let report = Report {
+        eval_set: path.to_string(),
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        summaries,
+        questions: results,
+    };
+    match output {
+        Some(output) => {
+            serde_json::to_writer_pretty(BufWriter::new(fs::File::create(output)?), &report)?;
+            println!("Wrote the report to {output}");
+        }
+        None => println!("{}", serde_json::to_string_pretty(&report.summaries)?),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retrieved(found: &[(&str, i64)]) -> Vec<Retrieved> {
+        found
+            .iter()
+            .map(|(doc_ref, segment)| Retrieved {
+                doc_ref: doc_ref.to_string(),
+                segment: *segment,
+                relevence: None,
+            })
+            .collect()
+    }
+
+    fn expected(doc_ref: &str, segment: Option<i64>) -> Expected {
+        Expected {
+            doc_ref: doc_ref.to_string(),
+            segment,
+        }
+    }
+
+    fn assert_scores(actual: (f64, f64, f64), expected: (f64, f64, f64)) {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert!(
+            close(actual.0, expected.0)
+                && close(actual.1, expected.1)
+                && close(actual.2, expected.2),
+            "{actual:?} != {expected:?}"
+        );
+    }
+
+    #[test]
+    fn score_ranks_expected_segments() {
+        let found = retrieved(&[("data/x.md", 0), ("data/a.md", 0), ("data/b.md", 1)]);
+        let dcg = 1f64 / 3f64.log2() + 1f64 / 4f64.log2();
+        let ideal = 1f64 + 1f64 / 3f64.log2();
+        assert_scores(
+            score(
+                &found,
+                &[expected("a.md", Some(0)), expected("b.md", Some(1))],
+                3,
+            ),
+            (1.0, 0.5, dcg / ideal),
+        );
+        // Only the first `k` count
+        assert_scores(
+            score(
+                &found,
+                &[expected("a.md", Some(0)), expected("b.md", Some(1))],
+                2,
+            ),
+            (0.5, 0.5, (1f64 / 3f64.log2()) / ideal),
+        );
+        assert_scores(
+            score(&found, &[expected("a.md", Some(1))], 3),
+            (0.0, 0.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn score_credits_a_whole_document_once() {
+        let found = retrieved(&[("data/a.md", 0), ("data/a.md", 3)]);
+        assert_scores(score(&found, &[expected("a.md", None)], 2), (1.0, 1.0, 1.0));
+        assert_scores(
+            score(&found, &[expected("a.md", None), expected("b.md", None)], 2),
+            (0.5, 1.0, 1f64 / (1f64 + 1f64 / 3f64.log2())),
+        );
+    }
+
+    #[test]
+    fn expected_paths_are_relative_to_a_directory() {
+        let handbook = expected("handbook.pdf", None);
+        assert!(handbook.matches("data/handbook.pdf", 4));
+        assert!(handbook.matches("handbook.pdf", 0));
+        assert!(!handbook.matches("data/old_handbook.pdf", 0));
+        assert!(!expected("handbook.pdf", Some(1)).matches("data/handbook.pdf", 2));
+    }
+}
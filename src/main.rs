@@ -1,52 +1,168 @@
+mod cache;
 mod cli;
 mod completion;
+mod dedup;
+mod embedder;
+mod eval;
+mod reembed;
+mod reranker;
+mod retrieval;
 mod routes;
 mod schemas;
 mod splitter;
+mod store;
 mod util;
 
-use crate::cli::{parse_args, start_repl, Mode};
-use crate::schemas::EncodingRequest;
-use crate::util::spawn_embedding_model;
+use crate::cache::EmbeddingCache;
+use crate::cli::{parse_args, start_repl, Command, EmbeddingConfig, Mode};
+use crate::embedder::{check_model_dir, fetch_model, model_dimension};
+use crate::eval::evaluate;
+use crate::reembed::{check_embedding_model, reembed};
+use crate::reranker::{reranker_channel, spawn_reranker};
+use crate::util::{encoding_channel, spawn_embedding_model};
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use axum::routing::get;
 use axum::Router;
 use splitter::TextSplitter;
 
-use sqlx::postgres::PgPoolOptions;
-
 use util::store_data;
 
-use crate::routes::{answer::answer_handler, ws::ws_handler};
-use crate::schemas::AppState;
+use crate::routes::{answer::answer_handler, health::health_handler, ws::ws_handler};
+use crate::schemas::{AppState, Collection, EncodingSender};
+use crate::store::Distance;
 
 #[tokio::main]
 async fn main() {
     dotenvy::dotenv().unwrap();
-    let db_uri = std::env::var("PG_URI").expect("DATABASE_URL is not set");
     let config = parse_args();
-    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<EncodingRequest>();
-    let state = AppState {
-        pool: PgPoolOptions::new()
-            .max_connections(10)
-            .connect(db_uri.as_str())
+    if config.command == Command::FetchModel {
+        let mut fetched: Vec<&EmbeddingConfig> = Vec::new();
+        for collection in &config.collections {
+            if fetched.contains(&&collection.embedding) {
+                continue;
+            }
+            fetch_model(&collection.embedding)
+                .await
+                .expect("Failed to fetch embedding model");
+            fetched.push(&collection.embedding);
+        }
+        return;
+    }
+    let db_uri = std::env::var("PG_URI").expect("DATABASE_URL is not set");
+    let store = store::connect(&db_uri, &config.vector)
+        .await
+        .expect("Failed to connect to the store");
+    if config.command == Command::Migrate || config.auto_migrate {
+        store.migrate().await.expect("Failed to migrate the schema");
+        if config.command == Command::Migrate {
+            println!("Schema is up to date");
+            return;
+        }
+    }
+    store.check_schema().await.unwrap();
+    // Shared by every collection that keeps its cache on, entries are told apart by model
+    let cache = Arc::new(EmbeddingCache::new(store.clone()));
+    // Collections embedding with the same settings share a worker, normalising happens on the
+    // sending side so it can differ between them
+    let mut workers: Vec<(EmbeddingConfig, EncodingSender)> = Vec::new();
+    let mut collections = HashMap::new();
+    for collection in &config.collections {
+        // On unit vectors the dot product is the cosine, which keeps its similarity within 0 to 1
+        let normalize = collection.distance != Distance::Euclidean;
+        let tx = match workers.iter().find(|(e, _)| *e == collection.embedding) {
+            Some((_, tx)) => EncodingSender {
+                normalize,
+                ..tx.clone()
+            },
+            None => {
+                check_model_dir(&collection.embedding).expect("Embedding model is not available");
+                let dimension =
+                    model_dimension(&collection.embedding).expect("Unknown embedding model");
+                let cache = collection.embedding.cache.then(|| cache.clone());
+                let (tx, rx) = encoding_channel(&collection.embedding, dimension, cache, normalize);
+                spawn_embedding_model(rx, collection.embedding.clone());
+                workers.push((collection.embedding.clone(), tx.clone()));
+                tx
+            }
+        };
+        store
+            .prepare(&collection.name, tx.dimension, collection.distance)
             .await
-            .unwrap(),
-        tx: tx.clone(),
+            .expect("Failed to prepare the store");
+        collections.insert(
+            collection.name.clone(),
+            Collection {
+                name: collection.name.clone(),
+                tx,
+                distance: collection.distance,
+                retrieval: collection.retrieval.clone(),
+            },
+        );
+    }
+    let reranker = config.reranker_model_dir.clone().map(|dir| {
+        let (reranker, rx) = reranker_channel(config.embedding.queue_size);
+        spawn_reranker(rx, dir);
+        reranker
+    });
+    let state = AppState {
+        store,
+        collections: Arc::new(collections),
+        collection: config.collection.clone(),
         req_client: reqwest::Client::new(),
+        reranker,
+        debug: config.debug,
     };
-    spawn_embedding_model(rx);
-    store_data(state.pool.clone(), tx, &config).await.unwrap();
+    if config.command == Command::Reembed {
+        for collection in &config.collections {
+            let tx = state.collections[&collection.name].tx.clone();
+            reembed(
+                state.store.as_ref(),
+                &collection.name,
+                collection.distance,
+                tx,
+            )
+            .await
+            .expect("Failed to re-embed documents");
+        }
+        return;
+    }
+    for collection in &config.collections {
+        let tx = state.collections[&collection.name].tx.clone();
+        check_embedding_model(state.store.as_ref(), &collection.name, &tx)
+            .await
+            .unwrap();
+        store_data(state.store.clone(), tx, collection)
+            .await
+            .unwrap();
+    }
+    if config.command == Command::Eval {
+        evaluate(&state, &config.eval_set, config.eval_output.as_deref())
+            .await
+            .expect("Failed to evaluate retrieval");
+        return;
+    }
 
     match config.mode {
         Mode::Offline => {
-            start_repl(state.tx, state.pool, state.req_client).await;
+            start_repl(
+                state.collections,
+                state.collection,
+                state.store,
+                state.req_client,
+                config.session,
+                state.reranker,
+                state.debug,
+            )
+            .await;
         }
         Mode::Online => {
             let app = Router::new()
                 .route("/answer", get(answer_handler))
                 .route("/ws", get(ws_handler))
+                .route("/health", get(health_handler))
                 .with_state(state);
 
             let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
@@ -0,0 +1,174 @@
+use std::error::Error;
+
+use crate::{
+    cli::VectorExtension,
+    schemas::{DocumentRef, EncodingSender},
+    store::{postgres::PgStore, Distance, VectorStore},
+    util::encode_batched,
+};
+
+const REEMBED_BATCH_SIZE: i64 = 256;
+
+/// Warns about segments of a collection embedded with another model or dimension, retrieval
+/// never compares against them.
+pub async fn check_embedding_model(
+    store: &dyn VectorStore,
+    collection: &str,
+    tx: &EncodingSender,
+) -> Result<(), Box<dyn Error>> {
+    let models = store.models(collection).await.map_err(|e| e.to_string())?;
+    let current = (Some(tx.model.as_str()), Some(tx.dimension as i32));
+    for (other, dimension, count) in models
+        .iter()
+        .filter(|(m, d, _)| (m.as_deref(), *d) != current)
+    {
+        eprintln!(
+            "{count} segments of {collection} were embedded with {} ({} dimensions) and are ignored by {} ({} dimensions), run `llm-chatbot reembed` to rebuild them",
+            other.as_deref().unwrap_or("an unknown model"),
+            dimension.map_or("unknown".to_string(), |d| d.to_string()),
+            tx.model,
+            tx.dimension
+        );
+    }
+    Ok(())
+}
+
+/// Re-encodes every segment of a collection made by another model from its `raw` text. On
+/// Postgres they go into a staging table, then the vectors and the index are swapped over in one
+/// transaction. An interrupted run picks up where it stopped, and a server still running the old
+/// model keeps answering until the swap.
+pub async fn reembed(
+    store: &dyn VectorStore,
+    collection: &str,
+    distance: Distance,
+    tx: EncodingSender,
+) -> Result<(), Box<dyn Error>> {
+    let Some(pg) = store.postgres() else {
+        return reembed_in_place(store, collection, tx).await;
+    };
+    let pool = pg.pool();
+    let pgvector = pg.extension() == &VectorExtension::PgVector;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS documents_reembed (id BIGINT PRIMARY KEY, embedding real[] NOT NULL, embedding_model TEXT NOT NULL)",
+    )
+    .execute(pool)
+    .await?;
+    // Left over from an interrupted run for a different model, dimension or collection
+    sqlx::query(
+        "DELETE FROM documents_reembed r USING documents d WHERE d.id = r.id AND (r.embedding_model <> $1 OR d.collection <> $2 OR array_length(r.embedding, 1) <> $3)",
+    )
+    .bind(&tx.model)
+    .bind(collection)
+    .bind(tx.dimension as i32)
+    .execute(pool)
+    .await?;
+
+    let mut last_id = 0i64;
+    let mut done = 0;
+    loop {
+        let rows: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT d.id, d.raw FROM documents d LEFT JOIN documents_reembed r ON r.id = d.id WHERE d.collection = $4 AND (d.embedding_model IS DISTINCT FROM $1 OR d.embedding_dim IS DISTINCT FROM $5) AND r.id IS NULL AND d.id > $2 ORDER BY d.id LIMIT $3",
+        )
+        .bind(&tx.model)
+        .bind(last_id)
+        .bind(REEMBED_BATCH_SIZE)
+        .bind(collection)
+        .bind(tx.dimension as i32)
+        .fetch_all(pool)
+        .await?;
+        if rows.is_empty() {
+            break;
+        }
+        last_id = rows.last().unwrap().0;
+        let embeddings =
+            encode_batched(&tx, rows.iter().map(|(_, raw)| raw.clone()).collect()).await?;
+        for ((id, _), embedding) in rows.iter().zip(embeddings) {
+            sqlx::query(
+                "INSERT INTO documents_reembed (id, embedding, embedding_model) VALUES ($1, $2, $3)",
+            )
+            .bind(id)
+            .bind(embedding)
+            .bind(&tx.model)
+            .execute(pool)
+            .await?;
+        }
+        done += rows.len();
+        println!(
+            "Re-embedded {done} segments of {collection} with {}",
+            tx.model
+        );
+    }
+
+    // The index is built for a fixed dimension, so it is rebuilt along with the vectors
+    let mut transaction = pool.begin().await?;
+    sqlx::query(&format!(
+        "DROP INDEX IF EXISTS {}",
+        PgStore::index_name(collection)
+    ))
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query(&format!(
+        "UPDATE documents d SET embedding = r.embedding{}, embedding_model = r.embedding_model, embedding_dim = $1 FROM documents_reembed r WHERE d.id = r.id",
+        if pgvector { "::vector" } else { "" }
+    ))
+    .bind(tx.dimension as i32)
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query(&pg.index_statement(collection, tx.dimension, distance))
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query("DROP TABLE documents_reembed")
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    println!(
+        "Swapped {done} segments of {collection} over to {}",
+        tx.model
+    );
+
+    check_embedding_model(store, collection, &tx).await
+}
+
+// Stores without a separate index are rewritten a batch at a time, an interrupted run skips what
+// was already done
+async fn reembed_in_place(
+    store: &dyn VectorStore,
+    collection: &str,
+    tx: EncodingSender,
+) -> Result<(), Box<dyn Error>> {
+    let mut done = 0;
+    loop {
+        let stale = store
+            .stale(
+                collection,
+                &tx.model,
+                tx.dimension,
+                REEMBED_BATCH_SIZE as usize,
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        if stale.is_empty() {
+            break;
+        }
+        let embeddings =
+            encode_batched(&tx, stale.iter().map(|doc| doc.raw.clone()).collect()).await?;
+        done += stale.len();
+        let updated = stale
+            .into_iter()
+            .zip(embeddings)
+            .map(|(doc, embedding)| DocumentRef {
+                embedding,
+                embedding_model: Some(tx.model.clone()),
+                embedding_dim: Some(tx.dimension as i32),
+                ..doc
+            })
+            .collect();
+        store.upsert(updated).await.map_err(|e| e.to_string())?;
+        println!(
+            "Re-embedded {done} segments of {collection} with {}",
+            tx.model
+        );
+    }
+
+    check_embedding_model(store, collection, &tx).await
+}
@@ -0,0 +1,152 @@
+use std::{error::Error, panic::catch_unwind, path::Path};
+
+use rust_bert::{
+    bert::{BertConfig, BertForSequenceClassification},
+    Config,
+};
+use rust_tokenizers::{
+    tokenizer::{BertTokenizer, Tokenizer, TruncationStrategy},
+    vocab::{BertVocab, Vocab},
+};
+use tch::{nn::VarStore, no_grad, Device, Kind, Tensor};
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc, oneshot},
+    task::spawn_blocking,
+};
+
+// Longest (question, passage) pair the model sees, in tokens
+const MAX_PAIR_LENGTH: usize = 512;
+
+pub type RerankResult = Result<Vec<f32>, String>;
+
+pub struct RerankRequest {
+    pub question: String,
+    pub passages: Vec<String>,
+    pub tx: oneshot::Sender<RerankResult>,
+}
+
+#[derive(Clone)]
+pub struct RerankSender {
+    pub tx: mpsc::Sender<RerankRequest>,
+}
+
+impl RerankSender {
+    /// Relevance of each passage to the question between 0 and 1, in the order given.
+    pub async fn score(
+        &self,
+        question: &str,
+        passages: Vec<String>,
+    ) -> Result<Vec<f32>, Box<dyn Error + Send + Sync>> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(RerankRequest {
+                question: question.to_string(),
+                passages,
+                tx,
+            })
+            .await
+            .map_err(|_| "Reranker is not running")?;
+        Ok(rx.await??)
+    }
+}
+
+/// A BERT cross-encoder such as `cross-encoder/ms-marco-MiniLM-L-6-v2`, loaded from a directory
+/// with `config.json`, `vocab.txt` and a converted `rust_model.ot`. Runs on the CPU.
+pub struct CrossEncoder {
+    model: BertForSequenceClassification,
+    tokenizer: BertTokenizer,
+    // Owns the weights the model refers to
+    _var_store: VarStore,
+}
+
+impl CrossEncoder {
+    pub fn new(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        // `from_file` panics on a missing or invalid file, which would take the worker with it
+        let path = dir.join("config.json");
+        let config = catch_unwind(|| BertConfig::from_file(&path))
+            .map_err(|_| format!("Missing or invalid {}", path.display()))?;
+        let tokenizer = BertTokenizer::from_file(dir.join("vocab.txt"), true, true)?;
+        let mut var_store = VarStore::new(Device::Cpu);
+        let model = BertForSequenceClassification::new(var_store.root(), &config)?;
+        var_store.load(dir.join("rust_model.ot"))?;
+        Ok(CrossEncoder {
+            model,
+            tokenizer,
+            _var_store: var_store,
+        })
+    }
+
+    pub fn score(&self, question: &str, passages: &[&str]) -> Result<Vec<f32>, Box<dyn Error>> {
+        if passages.is_empty() {
+            return Ok(vec![]);
+        }
+        let pairs: Vec<(&str, &str)> = passages.iter().map(|p| (question, *p)).collect();
+        let encoded = self.tokenizer.encode_pair_list(
+            &pairs,
+            MAX_PAIR_LENGTH,
+            &TruncationStrategy::LongestFirst,
+            0,
+        );
+        let length = encoded.iter().map(|e| e.token_ids.len()).max().unwrap_or(0);
+        let pad = self.tokenizer.vocab().token_to_id(BertVocab::pad_value());
+        let mut ids = Vec::with_capacity(encoded.len());
+        let mut types = Vec::with_capacity(encoded.len());
+        let mut masks = Vec::with_capacity(encoded.len());
+        for input in &encoded {
+            let padding = length - input.token_ids.len();
+            let mut token_ids = input.token_ids.clone();
+            token_ids.extend(std::iter::repeat(pad).take(padding));
+            let mut segment_ids: Vec<i64> = input.segment_ids.iter().map(|s| *s as i64).collect();
+            segment_ids.extend(std::iter::repeat(0).take(padding));
+            let mut mask = vec![1i64; input.token_ids.len()];
+            mask.extend(std::iter::repeat(0).take(padding));
+            ids.push(Tensor::from_slice(&token_ids));
+            types.push(Tensor::from_slice(&segment_ids));
+            masks.push(Tensor::from_slice(&mask));
+        }
+        let (ids, types, masks) = (
+            Tensor::stack(&ids, 0),
+            Tensor::stack(&types, 0),
+            Tensor::stack(&masks, 0),
+        );
+        let logits = no_grad(|| {
+            self.model
+                .forward_t(Some(&ids), Some(&masks), Some(&types), None, None, false)
+                .logits
+        });
+        // ms-marco cross-encoders have a single relevance logit
+        let scores = logits.select(1, 0).sigmoid().to_kind(Kind::Float);
+        Ok(Vec::<f32>::try_from(scores)?)
+    }
+}
+
+/// Runs the cross-encoder on a blocking thread, like the embedding worker. When the model fails
+/// to load every request is answered with the error, and retrieval keeps the search order.
+pub fn spawn_reranker(mut rx: mpsc::Receiver<RerankRequest>, dir: String) {
+    let handle = Handle::current();
+    spawn_blocking(move || {
+        let model = CrossEncoder::new(Path::new(&dir))
+            .map_err(|e| format!("Failed to load reranker from {dir}: {e}"));
+        if let Err(e) = &model {
+            eprintln!("{e}");
+        }
+        while let Some(request) = handle.block_on(rx.recv()) {
+            let result = match &model {
+                Ok(model) => {
+                    let passages: Vec<&str> = request.passages.iter().map(|p| p.as_str()).collect();
+                    model
+                        .score(&request.question, &passages)
+                        .map_err(|e| format!("Failed to rerank: {e}"))
+                }
+                Err(e) => Err(e.clone()),
+            };
+            let _ = request.tx.send(result);
+        }
+    });
+}
+
+pub fn reranker_channel(queue_size: usize) -> (RerankSender, mpsc::Receiver<RerankRequest>) {
+    let (tx, rx) = mpsc::channel(queue_size);
+    (RerankSender { tx }, rx)
+}
@@ -0,0 +1,572 @@
+use std::collections::{HashMap, HashSet};
+
+use futures_util::future::try_join_all;
+
+use crate::{
+    cli::{QueryStrategy, RetrievalConfig},
+    completion::{condense_question, hypothetical_answer, paraphrase_question},
+    reranker::RerankSender,
+    schemas::{Collection, DocumentRef, OpenAiCompletionMessage},
+    store::{by_relevence, Filter, SearchQuery, StoreError, VectorStore},
+    util::{cosine_similarity, generate_embedding_for_text},
+};
+
+/// The question to search for: the latest turn rewritten into a standalone question when there
+/// is a conversation to read it against, or as asked when rewriting is off or fails.
+pub async fn search_question(
+    client: &reqwest::Client,
+    history: &[OpenAiCompletionMessage],
+    question: &str,
+    config: &RetrievalConfig,
+    debug: bool,
+) -> String {
+    if !config.rewrite || history.is_empty() {
+        return question.to_string();
+    }
+    match condense_question(client, history, question)
+        .await
+        .map_err(|e| e.to_string())
+    {
+        Ok(rewritten) if !rewritten.is_empty() => {
+            if debug {
+                eprintln!("Rewrote {:?} to {rewritten:?}", question.trim());
+            }
+            rewritten
+        }
+        Ok(_) => question.to_string(),
+        Err(e) => {
+            eprintln!("Failed to rewrite the question, searching for it as asked: {e}");
+            question.to_string()
+        }
+    }
+}
+
+/// Segments found for a question and the context assembled from them for the prompt.
+pub struct Retrieved {
+    pub segments: Vec<DocumentRef>,
+    pub context: String,
+}
+
+/// Finds the segments of a collection closest to a question, shared by the REPL, `/answer` and
+/// `/ws`. Vector hits below the similarity cutoff are dropped before anything else reorders them.
+/// With a reranker a larger pool of candidates is rescored by it. The rest are kept, best first,
+/// while they fit the context budget, which never leaves out the best one. Only segments matching
+/// the filter are searched.
+pub async fn retrieve(
+    store: &dyn VectorStore,
+    collection: &Collection,
+    reranker: Option<&RerankSender>,
+    client: &reqwest::Client,
+    question: &str,
+    filter: &Filter,
+    config: &RetrievalConfig,
+) -> Result<Retrieved, StoreError> {
+    let tx = &collection.tx;
+    let texts = query_variants(client, question, config).await;
+    let embeddings = try_join_all(texts.iter().map(|text| async move {
+        generate_embedding_for_text(tx.clone(), text.clone())
+            .await
+            .map_err(|e| e.to_string())
+    }))
+    .await?;
+    let limit = candidates(config, reranker.is_some());
+    let results = try_join_all(embeddings.into_iter().zip(&texts).map(
+        |(embedding, text)| async move {
+            let query = SearchQuery {
+                collection: collection.name.clone(),
+                embedding,
+                model: tx.model.clone(),
+                filter: filter.clone(),
+                limit,
+                distance: collection.distance,
+            };
+            if config.hybrid {
+                let (by_vector, by_text) =
+                    tokio::try_join!(store.search(&query), store.text_search(&query, text))?;
+                Ok::<_, StoreError>(fuse(similar(by_vector, config), by_text, config, limit))
+            } else {
+                Ok(similar(store.search(&query).await?, config))
+            }
+        },
+    ))
+    .await?;
+    let mut found = merge(results, limit);
+    if let Some(reranker) = reranker.filter(|_| config.rerank) {
+        found = rerank(reranker, question, found).await;
+    }
+    if config.mmr {
+        found = mmr(found, config.top_k, config.mmr_lambda);
+    }
+    found.truncate(config.top_k);
+    let (segments, used) = within_budget(found, config);
+    let neighbours = neighbours(store, &collection.name, &segments, config, used).await?;
+    let context = assemble_context(&segments, neighbours);
+    Ok(Retrieved { segments, context })
+}
+
+// Texts searched for, the question first. Variants that can't be generated are left out.
+async fn query_variants(
+    client: &reqwest::Client,
+    question: &str,
+    config: &RetrievalConfig,
+) -> Vec<String> {
+    let mut texts = vec![question.to_string()];
+    let variants = match config.strategy {
+        QueryStrategy::Single => return texts,
+        QueryStrategy::MultiQuery => paraphrase_question(client, question, config.paraphrases)
+            .await
+            .map_err(|e| e.to_string()),
+        QueryStrategy::Hyde => hypothetical_answer(client, question)
+            .await
+            .map(|passage| vec![passage])
+            .map_err(|e| e.to_string()),
+    };
+    match variants {
+        Ok(variants) => texts.extend(variants.into_iter().filter(|v| !v.is_empty())),
+        Err(e) => eprintln!(
+            "Failed to generate {:?} queries, searching for the question only: {e}",
+            config.strategy
+        ),
+    }
+    texts
+}
+
+// One list of hits from the searches of every variant, a segment found by several keeps its best
+// relevence
+fn merge(results: Vec<Vec<DocumentRef>>, limit: usize) -> Vec<DocumentRef> {
+    let mut merged: Vec<DocumentRef> = Vec::new();
+    for doc in results.into_iter().flatten() {
+        match merged
+            .iter_mut()
+            .find(|d| d.doc_ref == doc.doc_ref && d.segment == doc.segment)
+        {
+            Some(found) if doc.relevence > found.relevence => *found = doc,
+            Some(_) => {}
+            None => merged.push(doc),
+        }
+    }
+    merged.sort_by(by_relevence);
+    merged.truncate(limit);
+    merged
+}
+
+// Candidates fetched from the store, more than `top_k` when a later stage picks among them
+fn candidates(config: &RetrievalConfig, reranker: bool) -> usize {
+    let mut limit = config.top_k;
+    if config.rerank && reranker {
+        limit = limit.max(config.rerank_candidates);
+    }
+    if config.mmr {
+        limit = limit.max(config.mmr_candidates);
+    }
+    limit
+}
+
+// Maximal marginal relevance: repeatedly picks the candidate with the best trade-off between its
+// relevence and its similarity to what was already picked
+fn mmr(mut candidates: Vec<DocumentRef>, k: usize, lambda: f32) -> Vec<DocumentRef> {
+    let mut selected: Vec<DocumentRef> = Vec::with_capacity(k);
+    while selected.len() < k && !candidates.is_empty() {
+        let score = |doc: &DocumentRef| {
+            let redundancy = selected
+                .iter()
+                .map(|s| cosine_similarity(&doc.embedding, &s.embedding))
+                .fold(0f32, f32::max);
+            lambda * doc.relevence.unwrap_or(0f32) - (1f32 - lambda) * redundancy
+        };
+        let (best, _) = candidates
+            .iter()
+            .enumerate()
+            .map(|(i, doc)| (i, score(doc)))
+            .fold(
+                (0, f32::MIN),
+                |best, next| if next.1 > best.1 { next } else { best },
+            );
+        selected.push(candidates.remove(best));
+    }
+    selected
+}
+
+// The best hits that fit the context budget and the characters they take. The best is kept even
+// when it alone is over budget, rather than no context.
+fn within_budget(found: Vec<DocumentRef>, config: &RetrievalConfig) -> (Vec<DocumentRef>, usize) {
+    let mut used = 0;
+    let mut segments: Vec<DocumentRef> = Vec::with_capacity(found.len());
+    for doc in found {
+        let size = doc.raw.chars().count();
+        if config
+            .context_budget
+            .is_some_and(|budget| !segments.is_empty() && used + size > budget)
+        {
+            break;
+        }
+        used += size;
+        segments.push(doc);
+    }
+    (segments, used)
+}
+
+// Vector hits at least as similar to the query as the cutoff. Done before fusion and reranking,
+// whose scores only order hits and aren't similarities.
+fn similar(found: Vec<DocumentRef>, config: &RetrievalConfig) -> Vec<DocumentRef> {
+    match config.min_similarity {
+        Some(min) => found
+            .into_iter()
+            .filter(|doc| doc.relevence.is_none_or(|similarity| similarity >= min))
+            .collect(),
+        None => found,
+    }
+}
+
+// Smooths out the gap between the first few ranks, the usual constant for reciprocal rank fusion
+const RRF_K: f32 = 60.0;
+
+// Reciprocal rank fusion of both result lists, scaled so a segment ranked first by both scores 1
+fn fuse(
+    by_vector: Vec<DocumentRef>,
+    by_text: Vec<DocumentRef>,
+    config: &RetrievalConfig,
+    limit: usize,
+) -> Vec<DocumentRef> {
+    let mut fused: Vec<DocumentRef> = Vec::new();
+    let mut scores: HashMap<(String, i64), f32> = HashMap::new();
+    for (results, weight) in [
+        (by_vector, config.vector_weight),
+        (by_text, config.text_weight),
+    ] {
+        for (rank, doc) in results.into_iter().enumerate() {
+            let key = (doc.doc_ref.clone(), doc.segment);
+            if !scores.contains_key(&key) {
+                fused.push(doc);
+            }
+            *scores.entry(key).or_default() += weight / (RRF_K + rank as f32 + 1.0);
+        }
+    }
+    let best = ((config.vector_weight + config.text_weight) / (RRF_K + 1.0)).max(f32::MIN_POSITIVE);
+    for doc in fused.iter_mut() {
+        doc.relevence = Some(scores[&(doc.doc_ref.clone(), doc.segment)] / best);
+    }
+    fused.sort_by(by_relevence);
+    fused.truncate(limit);
+    fused
+}
+
+// Orders candidates by the cross-encoder's score, which becomes their `relevence`. Keeps the
+// search order when the reranker fails, a worse answer beats none.
+async fn rerank(
+    reranker: &RerankSender,
+    question: &str,
+    mut found: Vec<DocumentRef>,
+) -> Vec<DocumentRef> {
+    let passages = found.iter().map(|doc| doc.raw.clone()).collect();
+    match reranker.score(question, passages).await {
+        Ok(scores) => {
+            for (doc, score) in found.iter_mut().zip(scores) {
+                doc.relevence = Some(score);
+            }
+            found.sort_by(by_relevence);
+        }
+        Err(e) => eprintln!("Reranking failed, keeping the search order: {e}"),
+    }
+    found
+}
+
+// Segments around each hit, closest hits first, as far as the context budget allows
+async fn neighbours(
+    store: &dyn VectorStore,
+    collection: &str,
+    segments: &[DocumentRef],
+    config: &RetrievalConfig,
+    mut used: usize,
+) -> Result<Vec<DocumentRef>, StoreError> {
+    if config.neighbours == 0 {
+        return Ok(vec![]);
+    }
+    let mut seen: HashSet<(&str, i64)> = segments
+        .iter()
+        .map(|doc| (doc.doc_ref.as_str(), doc.segment))
+        .collect();
+    let mut wanted: Vec<(&str, i64)> = Vec::new();
+    for distance in 1..=config.neighbours as i64 {
+        for doc in segments {
+            for segment in [doc.segment - distance, doc.segment + distance] {
+                if segment >= 0 && seen.insert((doc.doc_ref.as_str(), segment)) {
+                    wanted.push((doc.doc_ref.as_str(), segment));
+                }
+            }
+        }
+    }
+    let mut by_doc: HashMap<&str, Vec<i64>> = HashMap::new();
+    for (doc_ref, segment) in &wanted {
+        by_doc.entry(*doc_ref).or_default().push(*segment);
+    }
+    let mut found: HashMap<(String, i64), DocumentRef> = HashMap::new();
+    for (doc_ref, segments) in by_doc {
+        for doc in store.segments(collection, doc_ref, &segments).await? {
+            found.insert((doc.doc_ref.clone(), doc.segment), doc);
+        }
+    }
+    let mut neighbours = Vec::new();
+    for (doc_ref, segment) in wanted {
+        if let Some(doc) = found.remove(&(doc_ref.to_string(), segment)) {
+            if let Some(budget) = config.context_budget {
+                if used + doc.raw.chars().count() > budget {
+                    continue;
+                }
+                used += doc.raw.chars().count();
+            }
+            neighbours.push(doc);
+        }
+    }
+    Ok(neighbours)
+}
+
+/// Merges hits and their neighbours into one block per run of consecutive segments, in document
+/// order, each labelled with where it came from. Documents are ordered by their best hit.
+pub fn assemble_context(hits: &[DocumentRef], neighbours: Vec<DocumentRef>) -> String {
+    let mut documents: Vec<(String, f32, Vec<DocumentRef>)> = Vec::new();
+    for doc in hits.iter().cloned().chain(neighbours) {
+        let relevence = doc.relevence.unwrap_or(0f32);
+        match documents.iter_mut().find(|(d, _, _)| *d == doc.doc_ref) {
+            Some((_, best, segments)) => {
+                *best = best.max(relevence);
+                segments.push(doc);
+            }
+            None => documents.push((doc.doc_ref.clone(), relevence, vec![doc])),
+        }
+    }
+    documents.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut blocks = Vec::new();
+    for (_, _, mut segments) in documents {
+        segments.sort_by_key(|doc| doc.segment);
+        segments.dedup_by_key(|doc| doc.segment);
+        let mut run: Vec<DocumentRef> = Vec::new();
+        for doc in segments {
+            if run
+                .last()
+                .is_some_and(|last| last.segment + 1 != doc.segment)
+            {
+                blocks.push(block(&run));
+                run.clear();
+            }
+            run.push(doc);
+        }
+        if !run.is_empty() {
+            blocks.push(block(&run));
+        }
+    }
+    blocks.join("\n\n")
+}
+
+// One labelled excerpt from consecutive segments, with text the previous segment already covers cut
+fn block(run: &[DocumentRef]) -> String {
+    let first = &run[0];
+    let last = &run[run.len() - 1];
+    let label = match (first.start_line, last.end_line) {
+        (Some(start), Some(end)) => format!("{}, lines {start}-{end}", first.doc_ref),
+        _ if first.segment == last.segment => format!("{} #{}", first.doc_ref, first.segment),
+        _ => format!("{} #{}-{}", first.doc_ref, first.segment, last.segment),
+    };
+    let mut text = first.raw.clone();
+    for (previous, doc) in run.iter().zip(&run[1..]) {
+        let overlap = match (previous.end_offset, doc.start_offset) {
+            (Some(end), Some(start)) if start < end => (end - start) as usize,
+            _ => 0,
+        };
+        text.push('\n');
+        text.extend(doc.raw.chars().skip(overlap));
+    }
+    format!("Source: {label}\n{text}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(doc_ref: &str, segment: i64, relevence: Option<f32>) -> DocumentRef {
+        DocumentRef {
+            doc_ref: doc_ref.to_string(),
+            segment,
+            relevence,
+            raw: format!("{doc_ref} #{segment}"),
+            ..DocumentRef::default()
+        }
+    }
+
+    fn refs(found: &[DocumentRef]) -> Vec<(&str, i64)> {
+        found
+            .iter()
+            .map(|doc| (doc.doc_ref.as_str(), doc.segment))
+            .collect()
+    }
+
+    #[test]
+    fn fuse_ranks_hits_of_both_searches_first() {
+        let config = RetrievalConfig::default();
+        let fused = fuse(
+            vec![hit("a", 0, Some(0.9)), hit("b", 0, Some(0.8))],
+            vec![hit("b", 0, Some(0.1)), hit("c", 0, Some(0.2))],
+            &config,
+            10,
+        );
+        assert_eq!(refs(&fused), vec![("b", 0), ("a", 0), ("c", 0)]);
+        let best = 2f32 / (RRF_K + 1.0);
+        let expected = (1f32 / (RRF_K + 1.0) + 1f32 / (RRF_K + 2.0)) / best;
+        assert!((fused[0].relevence.unwrap() - expected).abs() < 1e-6);
+        assert!((fused[1].relevence.unwrap() - 0.5).abs() < 1e-6);
+
+        let both = fuse(
+            vec![hit("a", 0, None)],
+            vec![hit("a", 0, None)],
+            &config,
+            10,
+        );
+        assert!((both[0].relevence.unwrap() - 1f32).abs() < 1e-6);
+        assert_eq!(fuse(fused, vec![], &config, 2).len(), 2);
+    }
+
+    #[test]
+    fn fuse_weights_searches() {
+        let config = RetrievalConfig {
+            text_weight: 3.0,
+            ..RetrievalConfig::default()
+        };
+        let fused = fuse(
+            vec![hit("a", 0, None)],
+            vec![hit("b", 0, None)],
+            &config,
+            10,
+        );
+        assert_eq!(refs(&fused), vec![("b", 0), ("a", 0)]);
+    }
+
+    #[test]
+    fn similar_drops_hits_below_the_cutoff() {
+        let found = vec![
+            hit("a", 0, Some(0.7)),
+            hit("b", 0, Some(0.5)),
+            hit("c", 0, None),
+        ];
+        let config = RetrievalConfig {
+            min_similarity: Some(0.6),
+            ..RetrievalConfig::default()
+        };
+        assert_eq!(
+            refs(&similar(found.clone(), &config)),
+            vec![("a", 0), ("c", 0)]
+        );
+        assert_eq!(similar(found, &RetrievalConfig::default()).len(), 3);
+    }
+
+    #[test]
+    fn within_budget_keeps_the_best_hit() {
+        // Every raw text here is 4 characters
+        let found = vec![hit("a", 0, None), hit("b", 0, None), hit("c", 0, None)];
+        let budget = |budget| RetrievalConfig {
+            context_budget: Some(budget),
+            ..RetrievalConfig::default()
+        };
+        let (kept, used) = within_budget(found.clone(), &budget(9));
+        assert_eq!((refs(&kept), used), (vec![("a", 0), ("b", 0)], 8));
+        let (kept, used) = within_budget(found.clone(), &budget(2));
+        assert_eq!((refs(&kept), used), (vec![("a", 0)], 4));
+        let (kept, used) = within_budget(found, &RetrievalConfig::default());
+        assert_eq!((kept.len(), used), (3, 12));
+    }
+
+    #[test]
+    fn mmr_trades_relevence_for_novelty() {
+        let candidates = vec![
+            DocumentRef {
+                embedding: vec![1f32, 0f32],
+                ..hit("a", 0, Some(0.9))
+            },
+            DocumentRef {
+                embedding: vec![1f32, 0f32],
+                ..hit("a", 1, Some(0.85))
+            },
+            DocumentRef {
+                embedding: vec![0f32, 1f32],
+                ..hit("b", 0, Some(0.7))
+            },
+        ];
+        assert_eq!(
+            refs(&mmr(candidates.clone(), 2, 0.5)),
+            vec![("a", 0), ("b", 0)]
+        );
+        assert_eq!(
+            refs(&mmr(candidates.clone(), 2, 1.0)),
+            vec![("a", 0), ("a", 1)]
+        );
+        assert_eq!(mmr(candidates, 5, 0.5).len(), 3);
+    }
+
+    #[test]
+    fn candidates_grow_for_later_stages() {
+        let config = RetrievalConfig {
+            top_k: 4,
+            rerank: true,
+            rerank_candidates: 20,
+            mmr: true,
+            mmr_candidates: 30,
+            ..RetrievalConfig::default()
+        };
+        assert_eq!(candidates(&config, true), 30);
+        assert_eq!(
+            candidates(
+                &RetrievalConfig {
+                    mmr: false,
+                    ..config.clone()
+                },
+                false
+            ),
+            4
+        );
+        assert_eq!(
+            candidates(
+                &RetrievalConfig {
+                    mmr: false,
+                    ..config
+                },
+                true
+            ),
+            20
+        );
+    }
+
+    #[test]
+    fn assemble_context_merges_runs_per_document() {
+        let segment =
+            |doc_ref: &str, segment: i64, raw: &str, span: (i64, i64), relevence| DocumentRef {
+                raw: raw.to_string(),
+                start_offset: Some(span.0),
+                end_offset: Some(span.1),
+                ..hit(doc_ref, segment, relevence)
+            };
+        let hits = vec![
+            segment("y.md", 5, "best", (100, 104), Some(0.9)),
+            segment("x.md", 1, "one two", (0, 7), Some(0.5)),
+        ];
+        let neighbours = vec![
+            // Starts 3 characters before the end of segment 1
+            segment("x.md", 2, "two three", (4, 13), None),
+            segment("y.md", 7, "later", (200, 205), None),
+            DocumentRef {
+                start_line: Some(3),
+                end_line: Some(4),
+                ..segment("z.md", 0, "lines", (0, 5), None)
+            },
+        ];
+        assert_eq!(
+            assemble_context(&hits, neighbours),
+            [
+                "Source: y.md #5\nbest",
+                "Source: y.md #7\nlater",
+                "Source: x.md #1-2\none two\n three",
+                "Source: z.md, lines 3-4\nlines",
+            ]
+            .join("\n\n")
+        );
+    }
+}
@@ -1,39 +1,94 @@
 use axum::{
     extract::{Query, State},
     http::StatusCode,
+    Json,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use crate::{
+    cli::QueryStrategy,
     completion::Completion,
-    schemas::{AppState, DocumentRef},
-    util::{generate_embedding_for_text, sort_embeddings},
+    retrieval::retrieve,
+    schemas::{Answer, AppState, RetrievalOptions, Source, WorkerStatus},
+    store::Filter,
 };
 
 #[derive(Serialize, Deserialize)]
 pub struct Question {
     question: String,
+    // Defaults to the server's collection
+    collection: Option<String>,
+    // JSON object the segment metadata has to contain, e.g. `{"department":"hr"}`
+    metadata: Option<String>,
+    // JSON filter, e.g. `{"doc_ref":"docs/hr/","file_type":["md"],"modified_after":"2023-01-01"}`
+    filter: Option<String>,
+    top_k: Option<usize>,
+    min_similarity: Option<f32>,
+    context_budget: Option<usize>,
+    neighbours: Option<usize>,
+    hybrid: Option<bool>,
+    rerank: Option<bool>,
+    mmr: Option<bool>,
+    mmr_lambda: Option<f32>,
+    strategy: Option<QueryStrategy>,
 }
 
 pub async fn answer_handler(
     query: Query<Question>,
     State(state): State<AppState>,
-) -> Result<String, StatusCode> {
-    let embeddings = generate_embedding_for_text(state.tx, query.question.clone())
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let segments = sqlx::query_as::<_, DocumentRef>(
-        "SELECT *, embedding <-> $1 as relevence FROM documents ORDER BY relevence LIMIT 4",
+) -> Result<Json<Answer>, StatusCode> {
+    let collection = state
+        .collection(query.collection.as_deref())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let mut filter: Filter = match &query.filter {
+        Some(filter) => serde_json::from_str(filter).map_err(|_| StatusCode::BAD_REQUEST)?,
+        None => Filter::default(),
+    };
+    // Asked for explicitly, so not quietly skipped like a configured default
+    if query.rerank == Some(true) && state.reranker.is_none() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if let Some(metadata) = &query.metadata {
+        match serde_json::from_str(metadata) {
+            Ok(Value::Object(metadata)) => filter.metadata.extend(metadata),
+            _ => return Err(StatusCode::BAD_REQUEST),
+        }
+    }
+    let retrieval = collection.retrieval.with(&RetrievalOptions {
+        top_k: query.top_k,
+        min_similarity: query.min_similarity,
+        context_budget: query.context_budget,
+        neighbours: query.neighbours,
+        hybrid: query.hybrid,
+        rerank: query.rerank,
+        mmr: query.mmr,
+        mmr_lambda: query.mmr_lambda,
+        strategy: query.strategy,
+        ..RetrievalOptions::default()
+    });
+    let retrieved = retrieve(
+        state.store.as_ref(),
+        collection,
+        state.reranker.as_ref(),
+        &state.req_client,
+        &query.question,
+        &filter,
+        &retrieval,
     )
-    .bind(&embeddings)
-    .fetch_all(&state.pool)
     .await
-    .unwrap();
-    let segments = sort_embeddings(segments);
-    let completion = Completion::new(segments.join("\n"), &state.req_client, vec![]);
+    .map_err(|_| match collection.tx.status() {
+        WorkerStatus::Healthy => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    })?;
+    let sources = retrieved.segments.iter().map(Source::from).collect();
+    let completion = Completion::new(retrieved.context, &state.req_client, vec![]);
     let answer = completion
         .generate(query.question.clone())
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(answer.content)
+    Ok(Json(Answer {
+        answer: answer.content,
+        sources,
+    }))
 }
@@ -0,0 +1,46 @@
+use std::collections::BTreeMap;
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+
+use crate::{
+    cache::CacheStats,
+    schemas::{AppState, WorkerStatus},
+};
+
+#[derive(Serialize)]
+pub struct Health {
+    degraded: bool,
+    // Worker of the default collection
+    embedding_worker: WorkerStatus,
+    // Worker of every collection, by name
+    collections: BTreeMap<String, WorkerStatus>,
+    embedding_cache: Option<CacheStats>,
+}
+
+pub async fn health_handler(State(state): State<AppState>) -> (StatusCode, Json<Health>) {
+    let collections: BTreeMap<String, WorkerStatus> = state
+        .collections
+        .iter()
+        .map(|(name, collection)| (name.clone(), collection.tx.status()))
+        .collect();
+    let default = state.collection(None).unwrap();
+    let embedding_worker = default.tx.status();
+    let degraded = collections
+        .values()
+        .any(|status| *status != WorkerStatus::Healthy);
+    let status = if degraded {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (
+        status,
+        Json(Health {
+            degraded,
+            embedding_worker,
+            collections,
+            embedding_cache: default.tx.cache.as_ref().map(|cache| cache.stats()),
+        }),
+    )
+}
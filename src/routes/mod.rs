@@ -1,2 +1,3 @@
 pub mod answer;
+pub mod health;
 pub mod ws;
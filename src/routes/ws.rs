@@ -1,53 +1,157 @@
 use axum::{
     extract::{
         ws::{Message, WebSocketUpgrade},
-        State,
+        Query, State,
     },
     response::IntoResponse,
 };
 use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::Value;
 
 use crate::{
     completion::Completion,
-    schemas::{AppState, DocumentRef, OpenAiCompletionMessage},
-    util::{generate_embedding_for_text, sort_embeddings},
+    retrieval::{retrieve, search_question},
+    schemas::{Answer, AppState, OpenAiCompletionMessage, RetrievalOptions, Source, WsQuestion},
+    store::Filter,
 };
 
-pub async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
+#[derive(Deserialize)]
+pub struct Session {
+    // Resumes the chat history kept under this name, when the store keeps history
+    session: Option<String>,
+    // Collection questions are asked against, defaults to the server's
+    collection: Option<String>,
+}
+
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    Query(session): Query<Session>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
     ws.on_upgrade(|mut socket| async move {
         if socket.send(Message::Ping(vec![])).await.is_err() {
             return;
         }
         let (mut tx, mut rcv) = socket.split();
-        let mut history: Vec<OpenAiCompletionMessage> = Vec::new();
+        let mut history: Vec<OpenAiCompletionMessage> = match &session.session {
+            Some(session) => state.store.history(session).await.unwrap_or_default(),
+            None => Vec::new(),
+        };
+        // Scope every following question on this connection until a message replaces them
+        let mut collection = session
+            .collection
+            .clone()
+            .unwrap_or_else(|| state.collection.clone());
+        let mut filter = Filter::default();
         while let Some(Ok(msg)) = rcv.next().await {
             match msg {
                 Message::Text(msg) => {
-                    let embeddings =
-                        match generate_embedding_for_text(state.tx.clone(), msg.clone()).await {
-                            Ok(embedding) => embedding,
-                            Err(_) => return,
-                        };
-                    let segments = sqlx::query_as::<_, DocumentRef>(
-                        "SELECT *, embedding <-> $1 as relevence FROM documents ORDER BY relevence LIMIT 4",
+                    let question = match parse_question(msg) {
+                        Ok(question) => question,
+                        Err(e) => {
+                            let error = serde_json::json!({ "error": e.to_string() });
+                            if tx.send(Message::Text(error.to_string())).await.is_err() {
+                                return;
+                            }
+                            continue;
+                        }
+                    };
+                    // An unknown collection is refused rather than switched to, so the
+                    // connection keeps asking the one it had
+                    if let Some(name) = question.collection {
+                        if !state.collections.contains_key(&name) {
+                            let error =
+                                serde_json::json!({ "error": format!("Unknown collection {name}") });
+                            if tx.send(Message::Text(error.to_string())).await.is_err() {
+                                return;
+                            }
+                            continue;
+                        }
+                        collection = name;
+                    }
+                    if let Some(scope) = question.filter {
+                        filter = scope;
+                    }
+                    let (msg, options) = (question.question, question.options);
+                    if options.rerank == Some(true) && state.reranker.is_none() {
+                        let error = serde_json::json!({ "error": "No reranker model is configured" });
+                        if tx.send(Message::Text(error.to_string())).await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                    let Some(collection) = state.collection(Some(collection.as_str())) else {
+                        let error =
+                            serde_json::json!({ "error": format!("Unknown collection {collection}") });
+                        if tx.send(Message::Text(error.to_string())).await.is_err() {
+                            return;
+                        }
+                        continue;
+                    };
+                    let retrieval = collection.retrieval.with(&options);
+                    let question =
+                        search_question(&state.req_client, &history, &msg, &retrieval, state.debug)
+                            .await;
+                    let retrieved = match retrieve(
+                        state.store.as_ref(),
+                        collection,
+                        state.reranker.as_ref(),
+                        &state.req_client,
+                        &question,
+                        &filter,
+                        &retrieval,
                     )
-                    .bind(&embeddings)
-                    .fetch_all(&state.pool)
                     .await
-                    .unwrap();
-                    let segments = sort_embeddings(segments);
+                    {
+                        Ok(retrieved) => retrieved,
+                        Err(e) => {
+                            let error = serde_json::json!({ "error": e.to_string() });
+                            if tx.send(Message::Text(error.to_string())).await.is_err() {
+                                return;
+                            }
+                            continue;
+                        }
+                    };
+                    let sources = retrieved.segments.iter().map(Source::from).collect();
                     let completion =
-                        Completion::new(segments.join("\n"), &state.req_client, history.clone());
-                    let answer = match completion.generate(msg.clone()).await {
+                        Completion::new(retrieved.context, &state.req_client, history.clone());
+                    // As a string, the boxed error can't be held across the send
+                    let answer = match completion
+                        .generate(msg.clone())
+                        .await
+                        .map_err(|e| e.to_string())
+                    {
                         Ok(completion) => completion,
-                        Err(_) => return,
+                        Err(e) => {
+                            let error = serde_json::json!({ "error": e });
+                            if tx.send(Message::Text(error.to_string())).await.is_err() {
+                                return;
+                            }
+                            continue;
+                        }
                     };
-                    history.push(OpenAiCompletionMessage {
+                    let question = OpenAiCompletionMessage {
                         role: crate::schemas::OpenAiCompletionRole::User,
                         content: msg,
-                    });
+                    };
+                    if let Some(session) = &session.session {
+                        for message in [&question, &answer] {
+                            if let Err(e) = state.store.push_history(session, message).await {
+                                eprintln!("Unable to save chat history: {e}");
+                            }
+                        }
+                    }
+                    history.push(question);
                     history.push(answer.clone());
-                    tx.send(Message::Text(answer.content)).await.unwrap();
+                    let answer = Answer {
+                        answer: answer.content,
+                        sources,
+                    };
+                    let answer = serde_json::to_string(&answer).unwrap();
+                    if tx.send(Message::Text(answer)).await.is_err() {
+                        break;
+                    }
                 }
                 Message::Ping(_) => continue,
                 Message::Pong(_) => continue,
@@ -58,3 +162,41 @@ pub async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) ->
         }
     })
 }
+
+// Either a bare question or a JSON object with retrieval overrides. An object that doesn't parse
+// is an error to report, not a question to answer.
+fn parse_question(msg: String) -> Result<WsQuestion, serde_json::Error> {
+    match serde_json::from_str::<Value>(&msg) {
+        Ok(value @ Value::Object(_)) => serde_json::from_value(value),
+        _ => Ok(WsQuestion {
+            question: msg,
+            collection: None,
+            filter: None,
+            options: RetrievalOptions::default(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_text_is_the_question() {
+        let question = parse_question("What is the leave policy?".to_string()).unwrap();
+        assert_eq!(question.question, "What is the leave policy?");
+        assert!(question.filter.is_none());
+    }
+
+    #[test]
+    fn malformed_objects_are_rejected() {
+        let bad_date = r#"{ "question": "Leave?", "filter": { "modified_after": "last week" } }"#;
+        assert!(parse_question(bad_date.to_string()).is_err());
+        assert!(parse_question(r#"{ "top_k": 3 }"#.to_string()).is_err());
+        let question = parse_question(
+            r#"{ "question": "Leave?", "filter": { "doc_ref": "hr/" } }"#.to_string(),
+        )
+        .unwrap();
+        assert_eq!(question.filter.unwrap().doc_ref.as_deref(), Some("hr/"));
+    }
+}
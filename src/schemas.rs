@@ -1,26 +1,174 @@
 use serde::{Deserialize, Serialize};
-use sqlx::{Pool, Postgres};
-use tokio::sync::{mpsc::UnboundedSender, oneshot::Sender};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    cache::EmbeddingCache,
+    cli::{QueryStrategy, RetrievalConfig},
+    reranker::RerankSender,
+    store::{Distance, Filter, VectorStore},
+};
+use tokio::sync::{
+    mpsc::{Receiver, Sender as MpscSender},
+    oneshot::Sender,
+};
+
+pub type EncodingResult = Result<Vec<Vec<f32>>, String>;
 
 pub struct EncodingRequest {
     pub raw: Vec<String>,
-    pub tx: Sender<Vec<Vec<f32>>>,
+    pub tx: Sender<EncodingResult>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status", content = "error", rename_all = "snake_case")]
+pub enum WorkerStatus {
+    Starting,
+    Healthy,
+    // The worker failed and is restarting, with the error that caused it
+    Degraded(String),
+}
+
+/// Bounded queues feeding the embedding worker, interactive queries are always encoded before
+/// anything waiting on the ingestion queue.
+#[derive(Clone)]
+pub struct EncodingSender {
+    pub query: MpscSender<EncodingRequest>,
+    pub ingest: MpscSender<EncodingRequest>,
+    pub batch_size: usize,
+    pub health: Arc<RwLock<WorkerStatus>>,
+    // Identity of the model behind the worker, stored with every vector it produces
+    pub model: String,
+    pub dimension: usize,
+    pub cache: Option<Arc<EmbeddingCache>>,
+    // Identifies vectors of this model, backend and size in the cache
+    pub cache_source: String,
+    // Scale vectors to unit length, set for collections searched by cosine or dot product
+    pub normalize: bool,
+}
+
+impl EncodingSender {
+    pub fn status(&self) -> WorkerStatus {
+        self.health.read().unwrap().clone()
+    }
+}
+
+pub struct EncodingReceiver {
+    pub query: Receiver<EncodingRequest>,
+    pub ingest: Receiver<EncodingRequest>,
+    // A request taken off a queue that did not fit into the previous batch, and whether that was
+    // the query queue
+    pub pending: Option<(bool, EncodingRequest)>,
+    pub health: Arc<RwLock<WorkerStatus>>,
 }
 
 #[derive(Debug, sqlx::FromRow, Clone, Default)]
 pub struct DocumentRef {
+    pub collection: String,
     pub embedding: Vec<f32>,
     pub raw: String,
     pub relevence: Option<f32>,
     pub doc_ref: String,
     pub segment: i64,
+    pub start_offset: Option<i64>,
+    pub end_offset: Option<i64>,
+    pub start_line: Option<i32>,
+    pub end_line: Option<i32>,
+    pub metadata: Value,
+    pub embedding_model: Option<String>,
+    pub embedding_dim: Option<i32>,
+}
+
+/// Where a passage used to answer a question came from, character offsets are into the text
+/// extracted from `doc_ref` and lines are only known for plain text files.
+#[derive(Serialize, Debug, Clone)]
+pub struct Source {
+    pub doc_ref: String,
+    pub segment: i64,
+    pub start_offset: Option<i64>,
+    pub end_offset: Option<i64>,
+    pub start_line: Option<i32>,
+    pub end_line: Option<i32>,
+    pub relevence: Option<f32>,
+    pub metadata: Value,
+}
+
+impl From<&DocumentRef> for Source {
+    fn from(doc: &DocumentRef) -> Self {
+        Source {
+            doc_ref: doc.doc_ref.clone(),
+            segment: doc.segment,
+            start_offset: doc.start_offset,
+            end_offset: doc.end_offset,
+            start_line: doc.start_line,
+            end_line: doc.end_line,
+            relevence: doc.relevence,
+            metadata: doc.metadata.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Answer {
+    pub answer: String,
+    pub sources: Vec<Source>,
+}
+
+/// Per request overrides of the retrieval settings, from `/answer` parameters or `/ws` messages.
+#[derive(Deserialize, Debug, Default)]
+pub struct RetrievalOptions {
+    pub top_k: Option<usize>,
+    pub min_similarity: Option<f32>,
+    pub context_budget: Option<usize>,
+    pub neighbours: Option<usize>,
+    pub hybrid: Option<bool>,
+    pub rerank: Option<bool>,
+    pub mmr: Option<bool>,
+    pub mmr_lambda: Option<f32>,
+    pub rewrite: Option<bool>,
+    pub strategy: Option<QueryStrategy>,
+}
+
+/// A `/ws` message, plain text is taken as a question with the default settings. A collection or
+/// filter stays in place for the rest of the connection, a `{}` filter removes it.
+#[derive(Deserialize, Debug)]
+pub struct WsQuestion {
+    pub question: String,
+    pub collection: Option<String>,
+    pub filter: Option<Filter>,
+    #[serde(flatten)]
+    pub options: RetrievalOptions,
+}
+
+/// A knowledge base questions can be asked against, with the worker embedding them for its model
+/// and its retrieval defaults.
+#[derive(Clone)]
+pub struct Collection {
+    pub name: String,
+    pub tx: EncodingSender,
+    pub distance: Distance,
+    pub retrieval: RetrievalConfig,
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    pub pool: Pool<Postgres>,
-    pub tx: UnboundedSender<EncodingRequest>,
+    pub store: Arc<dyn VectorStore>,
+    pub collections: Arc<HashMap<String, Collection>>,
+    // Asked when a request doesn't name a collection
+    pub collection: String,
     pub req_client: reqwest::Client,
+    pub reranker: Option<RerankSender>,
+    pub debug: bool,
+}
+
+impl AppState {
+    /// The named collection, or the default one.
+    pub fn collection(&self, name: Option<&str>) -> Option<&Collection> {
+        self.collections.get(name.unwrap_or(&self.collection))
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
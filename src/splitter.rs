@@ -1,5 +1,41 @@
+use std::error::Error;
+
+use crate::{
+    schemas::EncodingSender,
+    util::{cosine_similarity, encode_batched},
+};
+
+/// A piece of the source text along with its character offsets, `source[start..end] == text`.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Chunk {
+    // Trims surrounding whitespace, keeping the offsets pointed at the remaining text
+    fn trimmed(source: &[char], mut start: usize, mut end: usize) -> Option<Self> {
+        while start < end && source[start].is_whitespace() {
+            start += 1;
+        }
+        while end > start && source[end - 1].is_whitespace() {
+            end -= 1;
+        }
+        if start == end {
+            return None;
+        }
+        Some(Chunk {
+            text: source[start..end].iter().collect(),
+            start,
+            end,
+        })
+    }
+}
+
 pub struct TextSplitter<'a> {
     source: &'a [char],
+    offset: usize,
     context_length: usize, // 128 * 4 -> where 4 is the number of chars per token
     splitter: Option<&'a str>,
 }
@@ -8,6 +44,7 @@ impl<'a> TextSplitter<'a> {
     pub fn new(source: &'a [char], context_length: usize, splitter: Option<&'a str>) -> Self {
         TextSplitter {
             source,
+            offset: 0,
             context_length,
             splitter,
         }
@@ -15,12 +52,13 @@ impl<'a> TextSplitter<'a> {
 }
 
 impl<'a> Iterator for TextSplitter<'a> {
-    type Item = String;
+    type Item = Chunk;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.source.len() == 0 {
             return None;
         }
+        let start = self.offset;
         let mut sl = String::new();
         loop {
             if self.source.len() == 0 {
@@ -28,14 +66,241 @@ impl<'a> Iterator for TextSplitter<'a> {
             }
             sl.push(self.source[0]);
             self.source = &self.source[1..];
+            self.offset += 1;
             if self.splitter.is_some() && sl.ends_with(self.splitter.unwrap()) {
                 sl = sl.trim_end_matches("\n").to_string();
                 break;
             }
-            if sl.len() > self.context_length && !sl.trim().is_empty() {
+            if self.offset >= start + self.context_length && !sl.trim().is_empty() {
                 break;
             }
         }
-        Some(String::from(sl))
+        let end = start + sl.chars().count();
+        Some(Chunk {
+            text: sl,
+            start,
+            end,
+        })
+    }
+}
+
+pub struct SemanticSplitter {
+    threshold: f32,
+    min_chunk: usize,
+    max_chunk: usize,
+    window: usize,
+}
+
+impl SemanticSplitter {
+    pub fn new(threshold: f32, min_chunk: usize, max_chunk: usize, window: usize) -> Self {
+        SemanticSplitter {
+            threshold,
+            min_chunk,
+            max_chunk,
+            window: window.max(1),
+        }
+    }
+
+    /// Splits `source` into chunks, starting a new chunk wherever the cosine similarity between
+    /// the sentence windows on either side of a boundary drops below the threshold.
+    pub async fn split(
+        &self,
+        source: &[char],
+        tx: EncodingSender,
+    ) -> Result<Vec<Chunk>, Box<dyn Error>> {
+        let sentences = self.sentences(source);
+        if sentences.len() < 2 {
+            return Ok(sentences
+                .iter()
+                .filter_map(|(start, end)| Chunk::trimmed(source, *start, *end))
+                .collect());
+        }
+        let embeddings = encode_batched(
+            &tx,
+            sentences
+                .iter()
+                .map(|(start, end)| source[*start..*end].iter().collect::<String>())
+                .map(|sentence| sentence.trim().to_string())
+                .collect(),
+        )
+        .await?;
+
+        let mut chunks: Vec<(usize, usize)> = Vec::new();
+        let (mut start, mut end) = sentences[0];
+        for i in 1..sentences.len() {
+            let left = mean(&embeddings[i.saturating_sub(self.window)..i]);
+            let right = mean(&embeddings[i..(i + self.window).min(embeddings.len())]);
+            let similarity = cosine_similarity(&left, &right);
+            let current = end - start;
+            let next = sentences[i].1 - start;
+            if (similarity < self.threshold && current >= self.min_chunk) || next > self.max_chunk {
+                chunks.push((start, end));
+                start = sentences[i].0;
+            }
+            end = sentences[i].1;
+        }
+        // A short trailing chunk is folded into the previous one when it fits.
+        let merge = matches!(chunks.last(), Some((prev_start, _))
+            if end - start < self.min_chunk && end - prev_start <= self.max_chunk);
+        if merge {
+            let (prev_start, _) = chunks.pop().unwrap();
+            chunks.push((prev_start, end));
+        } else {
+            chunks.push((start, end));
+        }
+
+        Ok(chunks
+            .iter()
+            .filter_map(|(start, end)| Chunk::trimmed(source, *start, *end))
+            .collect())
+    }
+
+    // Sentence boundaries are `.`, `!` or `?` followed by whitespace, and blank lines. Sentences
+    // longer than `max_chunk` are cut into `max_chunk` sized pieces.
+    fn sentences(&self, source: &[char]) -> Vec<(usize, usize)> {
+        let mut sentences = Vec::new();
+        let mut start = 0;
+        let mut i = 0;
+        while i < source.len() {
+            let next = source.get(i + 1);
+            let boundary = match source[i] {
+                '.' | '!' | '?' => next.is_none_or(|c| c.is_whitespace()),
+                '\n' => next == Some(&'\n'),
+                _ => false,
+            };
+            i += 1;
+            if boundary || i == source.len() {
+                while i < source.len() && source[i].is_whitespace() {
+                    i += 1;
+                }
+                if source[start..i].iter().any(|c| !c.is_whitespace()) {
+                    sentences.push((start, i));
+                }
+                start = i;
+            }
+        }
+        let mut bounded = Vec::new();
+        for (start, end) in sentences {
+            let mut from = start;
+            while end - from > self.max_chunk {
+                bounded.push((from, from + self.max_chunk));
+                from += self.max_chunk;
+            }
+            bounded.push((from, end));
+        }
+        bounded
+    }
+}
+
+fn mean(embeddings: &[Vec<f32>]) -> Vec<f32> {
+    let mut sum = vec![0f32; embeddings[0].len()];
+    for embedding in embeddings {
+        for (acc, v) in sum.iter_mut().zip(embedding) {
+            *acc += v;
+        }
+    }
+    sum.iter().map(|v| v / embeddings.len() as f32).collect()
+}
+
+/// Maps character offsets of a source to 1-based line numbers.
+pub struct LineIndex {
+    newlines: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(source: &[char]) -> Self {
+        LineIndex {
+            newlines: source
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| **c == '\n')
+                .map(|(i, _)| i)
+                .collect(),
+        }
+    }
+
+    pub fn line(&self, offset: usize) -> usize {
+        self.newlines.partition_point(|i| *i < offset) + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cli::EmbeddingConfig, util::encoding_channel};
+
+    fn chars(text: &str) -> Vec<char> {
+        text.chars().collect()
+    }
+
+    fn spans(chunks: &[Chunk]) -> Vec<(&str, usize, usize)> {
+        chunks
+            .iter()
+            .map(|chunk| (chunk.text.as_str(), chunk.start, chunk.end))
+            .collect()
+    }
+
+    #[test]
+    fn text_splitter_splits_at_the_separator() {
+        let source = chars("a\n\nb");
+        let chunks: Vec<Chunk> = TextSplitter::new(&source, 100, Some("\n\n")).collect();
+        assert_eq!(spans(&chunks), vec![("a", 0, 1), ("b", 3, 4)]);
+    }
+
+    #[test]
+    fn text_splitter_cuts_at_the_context_length() {
+        let source = chars("abcdef");
+        let chunks: Vec<Chunk> = TextSplitter::new(&source, 3, None).collect();
+        assert_eq!(spans(&chunks), vec![("abc", 0, 3), ("def", 3, 6)]);
+    }
+
+    #[test]
+    fn sentences_are_cut_at_max_chunk() {
+        let splitter = SemanticSplitter::new(0.5, 1, 4, 1);
+        assert_eq!(
+            splitter.sentences(&chars("abcdefghij")),
+            vec![(0, 4), (4, 8), (8, 10)]
+        );
+        assert_eq!(
+            splitter.sentences(&chars("Hi. Yo!\n\nOk")),
+            vec![(0, 4), (4, 9), (9, 11)]
+        );
+    }
+
+    #[test]
+    fn line_index_counts_from_one() {
+        let index = LineIndex::new(&chars("a\nb\nc"));
+        assert_eq!([0, 1, 2, 4].map(|offset| index.line(offset)), [1, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn semantic_splitter_splits_where_the_topic_changes() {
+        let (tx, mut rx) = encoding_channel(&EmbeddingConfig::default(), 2, None, false);
+        // Stands in for the model, sentences about cats point one way and the rest another
+        tokio::spawn(async move {
+            while let Some(request) = rx.ingest.recv().await {
+                let embeddings = request
+                    .raw
+                    .iter()
+                    .map(|sentence| match sentence.contains("Cats") {
+                        true => vec![1f32, 0f32],
+                        false => vec![0f32, 1f32],
+                    })
+                    .collect();
+                let _ = request.tx.send(Ok(embeddings));
+            }
+        });
+        let source = chars("Cats purr. Cats nap. Stocks fell. Stocks rose.");
+        let chunks = SemanticSplitter::new(0.5, 1, 1000, 1)
+            .split(&source, tx)
+            .await
+            .unwrap();
+        assert_eq!(
+            spans(&chunks),
+            vec![
+                ("Cats purr. Cats nap.", 0, 20),
+                ("Stocks fell. Stocks rose.", 21, 46)
+            ]
+        );
     }
 }
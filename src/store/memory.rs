@@ -0,0 +1,368 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use async_trait::async_trait;
+use serde_json::Value;
+
+use crate::{dedup::simhash, schemas::DocumentRef};
+
+use super::{
+    by_relevence, with_duplicate, without_duplicates_of, SearchQuery, StoreError, VectorStore,
+};
+
+/// Brute force search over segments held in memory, for running without a database.
+#[derive(Default)]
+pub struct MemoryStore {
+    documents: RwLock<Vec<DocumentRef>>,
+    cache: RwLock<HashMap<String, Vec<f32>>>,
+}
+
+#[async_trait]
+impl VectorStore for MemoryStore {
+    async fn upsert(&self, documents: Vec<DocumentRef>) -> Result<(), StoreError> {
+        let mut stored = self.documents.write().unwrap();
+        for doc in documents {
+            stored.retain(|d| {
+                !(d.collection == doc.collection
+                    && d.doc_ref == doc.doc_ref
+                    && d.segment == doc.segment)
+            });
+            stored.push(doc);
+        }
+        Ok(())
+    }
+
+    async fn delete_doc(&self, collection: &str, doc_ref: &str) -> Result<u64, StoreError> {
+        let mut stored = self.documents.write().unwrap();
+        let before = stored.len();
+        stored.retain(|d| !(d.collection == collection && d.doc_ref == doc_ref));
+        Ok((before - stored.len()) as u64)
+    }
+
+    async fn replace_doc(
+        &self,
+        collection: &str,
+        doc_ref: &str,
+        documents: Vec<DocumentRef>,
+    ) -> Result<(), StoreError> {
+        let mut stored = self.documents.write().unwrap();
+        stored.retain(|d| !(d.collection == collection && d.doc_ref == doc_ref));
+        stored.extend(documents);
+        Ok(())
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<Vec<DocumentRef>, StoreError> {
+        let mut found: Vec<DocumentRef> = self
+            .documents
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|d| d.collection == query.collection)
+            .filter(|d| d.embedding_model.as_deref() == Some(query.model.as_str()))
+            .filter(|d| d.embedding_dim == Some(query.embedding.len() as i32))
+            .filter(|d| query.filter.matches(d))
+            .map(|d| DocumentRef {
+                relevence: Some(
+                    query
+                        .distance
+                        .similarity(query.distance.between(&d.embedding, &query.embedding)),
+                ),
+                ..d.clone()
+            })
+            .collect();
+        found.sort_by(by_relevence);
+        found.truncate(query.limit);
+        Ok(found)
+    }
+
+    async fn segments(
+        &self,
+        collection: &str,
+        doc_ref: &str,
+        segments: &[i64],
+    ) -> Result<Vec<DocumentRef>, StoreError> {
+        Ok(self
+            .documents
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|d| d.collection == collection && d.doc_ref == doc_ref)
+            .filter(|d| segments.contains(&d.segment))
+            .cloned()
+            .collect())
+    }
+
+    async fn count(&self) -> Result<i64, StoreError> {
+        Ok(self.documents.read().unwrap().len() as i64)
+    }
+
+    async fn models(
+        &self,
+        collection: &str,
+    ) -> Result<Vec<(Option<String>, Option<i32>, i64)>, StoreError> {
+        let mut models: HashMap<(Option<String>, Option<i32>), i64> = HashMap::new();
+        let documents = self.documents.read().unwrap();
+        for doc in documents.iter().filter(|d| d.collection == collection) {
+            *models
+                .entry((doc.embedding_model.clone(), doc.embedding_dim))
+                .or_default() += 1;
+        }
+        Ok(models
+            .into_iter()
+            .map(|((model, dimension), count)| (model, dimension, count))
+            .collect())
+    }
+
+    async fn fingerprints(&self, collection: &str) -> Result<Vec<(String, i64, u64)>, StoreError> {
+        Ok(self
+            .documents
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|d| d.collection == collection)
+            .map(|d| (d.doc_ref.clone(), d.segment, simhash(&d.raw)))
+            .collect())
+    }
+
+    async fn add_duplicate(
+        &self,
+        collection: &str,
+        doc_ref: &str,
+        segment: i64,
+        duplicate: Value,
+    ) -> Result<(), StoreError> {
+        let mut stored = self.documents.write().unwrap();
+        for doc in stored
+            .iter_mut()
+            .filter(|d| d.collection == collection && d.doc_ref == doc_ref && d.segment == segment)
+        {
+            doc.metadata = with_duplicate(&doc.metadata, duplicate.clone());
+        }
+        Ok(())
+    }
+
+    async fn remove_duplicates(&self, collection: &str, doc_ref: &str) -> Result<(), StoreError> {
+        let mut stored = self.documents.write().unwrap();
+        for doc in stored.iter_mut().filter(|d| d.collection == collection) {
+            if let Some(metadata) = without_duplicates_of(&doc.metadata, doc_ref) {
+                doc.metadata = metadata;
+            }
+        }
+        Ok(())
+    }
+
+    async fn cached(&self, keys: &[String]) -> Result<HashMap<String, Vec<f32>>, StoreError> {
+        let cache = self.cache.read().unwrap();
+        Ok(keys
+            .iter()
+            .filter_map(|key| cache.get(key).map(|e| (key.clone(), e.clone())))
+            .collect())
+    }
+
+    async fn cache(
+        &self,
+        _model: &str,
+        entries: Vec<(String, Vec<f32>)>,
+    ) -> Result<(), StoreError> {
+        self.cache.write().unwrap().extend(entries);
+        Ok(())
+    }
+
+    async fn stale(
+        &self,
+        collection: &str,
+        model: &str,
+        dimension: usize,
+        limit: usize,
+    ) -> Result<Vec<DocumentRef>, StoreError> {
+        Ok(self
+            .documents
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|d| d.collection == collection)
+            .filter(|d| {
+                d.embedding_model.as_deref() != Some(model)
+                    || d.embedding_dim != Some(dimension as i32)
+            })
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    fn persistent(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::store::{Distance, Filter};
+
+    fn doc(collection: &str, doc_ref: &str, segment: i64, embedding: Vec<f32>) -> DocumentRef {
+        DocumentRef {
+            collection: collection.to_string(),
+            embedding,
+            raw: format!("{doc_ref} #{segment}"),
+            doc_ref: doc_ref.to_string(),
+            segment,
+            metadata: json!({}),
+            embedding_model: Some("test".to_string()),
+            embedding_dim: Some(2),
+            ..DocumentRef::default()
+        }
+    }
+
+    fn query(collection: &str, embedding: Vec<f32>, limit: usize) -> SearchQuery {
+        SearchQuery {
+            collection: collection.to_string(),
+            embedding,
+            model: "test".to_string(),
+            filter: Filter::default(),
+            limit,
+            distance: Distance::Cosine,
+        }
+    }
+
+    #[tokio::test]
+    async fn search_ranks_by_similarity_within_a_collection() {
+        let store = MemoryStore::default();
+        store
+            .upsert(vec![
+                doc("hr", "a.md", 0, vec![1f32, 0f32]),
+                doc("hr", "a.md", 1, vec![0f32, 1f32]),
+                doc("hr", "b.md", 0, vec![0.8f32, 0.6f32]),
+                doc("eng", "c.md", 0, vec![1f32, 0f32]),
+                DocumentRef {
+                    embedding_model: Some("other".to_string()),
+                    ..doc("hr", "d.md", 0, vec![1f32, 0f32])
+                },
+            ])
+            .await
+            .unwrap();
+        let found = store
+            .search(&query("hr", vec![1f32, 0f32], 2))
+            .await
+            .unwrap();
+        let refs: Vec<(&str, i64)> = found
+            .iter()
+            .map(|d| (d.doc_ref.as_str(), d.segment))
+            .collect();
+        assert_eq!(refs, vec![("a.md", 0), ("b.md", 0)]);
+        assert!((found[0].relevence.unwrap() - 1f32).abs() < 1e-6);
+        assert!(found[1].relevence.unwrap() < found[0].relevence.unwrap());
+    }
+
+    #[tokio::test]
+    async fn search_skips_vectors_of_another_dimension() {
+        let store = MemoryStore::default();
+        store
+            .upsert(vec![
+                doc("hr", "a.md", 0, vec![1f32, 0f32]),
+                DocumentRef {
+                    embedding_dim: Some(3),
+                    ..doc("hr", "b.md", 0, vec![1f32, 0f32, 0f32])
+                },
+            ])
+            .await
+            .unwrap();
+        let found = store
+            .search(&query("hr", vec![1f32, 0f32], 5))
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].doc_ref, "a.md");
+    }
+
+    #[tokio::test]
+    async fn stale_finds_other_models_and_dimensions() {
+        let store = MemoryStore::default();
+        store
+            .upsert(vec![
+                doc("hr", "a.md", 0, vec![1f32, 0f32]),
+                DocumentRef {
+                    embedding_model: Some("other".to_string()),
+                    ..doc("hr", "b.md", 0, vec![1f32, 0f32])
+                },
+                DocumentRef {
+                    embedding_dim: Some(3),
+                    ..doc("hr", "c.md", 0, vec![1f32, 0f32, 0f32])
+                },
+            ])
+            .await
+            .unwrap();
+        let mut stale: Vec<String> = store
+            .stale("hr", "test", 2, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|d| d.doc_ref)
+            .collect();
+        stale.sort();
+        assert_eq!(stale, vec!["b.md", "c.md"]);
+    }
+
+    #[tokio::test]
+    async fn upsert_replaces_and_delete_removes_a_collections_document() {
+        let store = MemoryStore::default();
+        store
+            .upsert(vec![
+                doc("hr", "a.md", 0, vec![1f32, 0f32]),
+                doc("eng", "a.md", 0, vec![1f32, 0f32]),
+            ])
+            .await
+            .unwrap();
+        store
+            .upsert(vec![doc("hr", "a.md", 0, vec![0f32, 1f32])])
+            .await
+            .unwrap();
+        assert_eq!(store.count().await.unwrap(), 2);
+        let stored = store.segments("hr", "a.md", &[0]).await.unwrap();
+        assert_eq!(stored[0].embedding, vec![0f32, 1f32]);
+        assert_eq!(store.delete_doc("hr", "a.md").await.unwrap(), 1);
+        assert!(store.segments("hr", "a.md", &[0]).await.unwrap().is_empty());
+        assert_eq!(store.segments("eng", "a.md", &[0]).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn replace_doc_drops_segments_the_new_version_lacks() {
+        let store = MemoryStore::default();
+        store
+            .upsert(vec![
+                doc("hr", "a.md", 0, vec![1f32, 0f32]),
+                doc("hr", "a.md", 1, vec![0f32, 1f32]),
+                doc("eng", "a.md", 1, vec![0f32, 1f32]),
+            ])
+            .await
+            .unwrap();
+        store
+            .replace_doc("hr", "a.md", vec![doc("hr", "a.md", 0, vec![0f32, 1f32])])
+            .await
+            .unwrap();
+        let stored = store.segments("hr", "a.md", &[0, 1]).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].embedding, vec![0f32, 1f32]);
+        assert_eq!(store.segments("eng", "a.md", &[1]).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn duplicates_are_merged_and_removed() {
+        let store = MemoryStore::default();
+        store
+            .upsert(vec![doc("hr", "a.md", 0, vec![1f32, 0f32])])
+            .await
+            .unwrap();
+        let duplicate = json!({ "doc_ref": "b.md", "segment": 3 });
+        store
+            .add_duplicate("hr", "a.md", 0, duplicate.clone())
+            .await
+            .unwrap();
+        let stored = store.segments("hr", "a.md", &[0]).await.unwrap();
+        assert_eq!(stored[0].metadata["duplicates"], json!([duplicate]));
+        store.remove_duplicates("hr", "b.md").await.unwrap();
+        let stored = store.segments("hr", "a.md", &[0]).await.unwrap();
+        assert_eq!(stored[0].metadata["duplicates"], json!([]));
+    }
+}
@@ -0,0 +1,646 @@
+pub mod memory;
+pub mod postgres;
+pub mod sqlite;
+
+use std::{cmp::Ordering, collections::HashMap, error::Error, sync::Arc};
+
+use async_trait::async_trait;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    cli::VectorConfig,
+    schemas::{DocumentRef, OpenAiCompletionMessage},
+    util::{cosine_similarity, dot_product, euclidean_distance},
+};
+
+use self::{memory::MemoryStore, postgres::PgStore, sqlite::SqliteStore};
+
+pub type StoreError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Distance {
+    #[default]
+    Euclidean,
+    Cosine,
+    // Negative inner product, like pgvector's `<#>`
+    Dot,
+}
+
+impl Distance {
+    // For stores that search by brute force
+    pub fn between(&self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Distance::Euclidean => euclidean_distance(a, b),
+            Distance::Cosine => 1f32 - cosine_similarity(a, b),
+            Distance::Dot => -dot_product(a, b),
+        }
+    }
+
+    /// Turns a distance into a similarity in [0, 1], higher is closer. Cosine and dot product
+    /// rely on the normalised vectors they are searched with, rounding outside the range is clamped.
+    pub fn similarity(&self, distance: f32) -> f32 {
+        let similarity = match self {
+            Distance::Euclidean => 1f32 / (1f32 + distance),
+            Distance::Cosine => 1f32 - distance / 2f32,
+            Distance::Dot => (1f32 - distance) / 2f32,
+        };
+        similarity.clamp(0f32, 1f32)
+    }
+}
+
+/// Same semantics as Postgres' `jsonb @> jsonb`.
+pub fn json_contains(haystack: &Value, needle: &Value) -> bool {
+    match (haystack, needle) {
+        (Value::Object(haystack), Value::Object(needle)) => needle.iter().all(|(key, value)| {
+            haystack
+                .get(key)
+                .is_some_and(|found| json_contains(found, value))
+        }),
+        (Value::Array(haystack), Value::Array(needle)) => needle
+            .iter()
+            .all(|value| haystack.iter().any(|found| json_contains(found, value))),
+        // Postgres only lets an array contain a bare scalar at the top level, which is always an
+        // object here, so `{"tags": ["a"]}` does not contain `{"tags": "a"}`
+        (haystack, needle) => haystack == needle,
+    }
+}
+
+/// Restricts retrieval to part of the corpus, every condition that is set has to hold.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Filter {
+    // Prefix of `doc_ref`, or a glob with `*` and `?` matching all of it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub doc_ref: Option<String>,
+    // Any of these file extensions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_type: Option<Vec<String>>,
+    // Bounds on the file's modification time, unix seconds or `YYYY-MM-DD`
+    #[serde(
+        default,
+        deserialize_with = "date",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub modified_after: Option<i64>,
+    #[serde(
+        default,
+        deserialize_with = "date",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub modified_before: Option<i64>,
+    // Object the segment metadata has to contain
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub metadata: Map<String, Value>,
+}
+
+impl Filter {
+    /// Same semantics as the SQL stores, for stores that filter in process.
+    pub fn matches(&self, doc: &DocumentRef) -> bool {
+        let doc_ref = match &self.doc_ref {
+            Some(pattern) if is_glob(pattern) => {
+                let pattern: Vec<char> = pattern.chars().collect();
+                let doc_ref: Vec<char> = doc.doc_ref.chars().collect();
+                glob_matches(&pattern, &doc_ref)
+            }
+            Some(prefix) => doc.doc_ref.starts_with(prefix.as_str()),
+            None => true,
+        };
+        let file_type = match &self.file_type {
+            Some(types) => doc
+                .metadata
+                .get("file_type")
+                .and_then(|t| t.as_str())
+                .is_some_and(|t| types.iter().any(|wanted| wanted == t)),
+            None => true,
+        };
+        // Compared as numbers like the Postgres store does, anything else never matches
+        let modified = doc.metadata.get("modified").and_then(|m| m.as_f64());
+        let after = match self.modified_after {
+            Some(after) => modified.is_some_and(|m| m >= after as f64),
+            None => true,
+        };
+        let before = match self.modified_before {
+            Some(before) => modified.is_some_and(|m| m < before as f64),
+            None => true,
+        };
+        doc_ref
+            && file_type
+            && after
+            && before
+            && json_contains(&doc.metadata, &Value::Object(self.metadata.clone()))
+    }
+
+    /// `doc_ref` as a SQL `LIKE` pattern.
+    pub fn doc_ref_pattern(&self) -> Option<String> {
+        self.doc_ref.as_ref().map(|pattern| {
+            let mut like = String::new();
+            for c in pattern.chars() {
+                match c {
+                    '%' | '_' | '\\' => {
+                        like.push('\\');
+                        like.push(c);
+                    }
+                    '*' => like.push('%'),
+                    '?' => like.push('_'),
+                    c => like.push(c),
+                }
+            }
+            if !is_glob(pattern) {
+                like.push('%');
+            }
+            like
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Filter::default()
+    }
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+// Over characters, like `LIKE` whose `_` matches one character however many bytes it takes
+fn glob_matches(pattern: &[char], text: &[char]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            glob_matches(&pattern[1..], text)
+                || (!text.is_empty() && glob_matches(pattern, &text[1..]))
+        }
+        (Some('?'), Some(_)) => glob_matches(&pattern[1..], &text[1..]),
+        (Some(p), Some(t)) if p == t => glob_matches(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+fn date<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
+    match Option::<Value>::deserialize(deserializer)? {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Number(seconds)) => seconds
+            .as_i64()
+            .map(Some)
+            .ok_or_else(|| D::Error::custom("Dates are whole unix seconds")),
+        Some(Value::String(date)) => parse_date(&date)
+            .map(Some)
+            .ok_or_else(|| D::Error::custom(format!("Invalid date {date}, expected YYYY-MM-DD"))),
+        Some(other) => Err(D::Error::custom(format!("Invalid date {other}"))),
+    }
+}
+
+/// Orders segments by `relevence`, best first. Missing and NaN scores rank last rather than
+/// failing the comparison.
+pub fn by_relevence(a: &DocumentRef, b: &DocumentRef) -> Ordering {
+    let rank = |doc: &DocumentRef| doc.relevence.filter(|r| !r.is_nan()).unwrap_or(f32::MIN);
+    rank(b).total_cmp(&rank(a))
+}
+
+/// `duplicates` metadata without the references to `doc_ref`, `None` when there are none.
+pub fn without_duplicates_of(metadata: &Value, doc_ref: &str) -> Option<Value> {
+    let duplicates = metadata.get("duplicates")?.as_array()?;
+    let kept: Vec<Value> = duplicates
+        .iter()
+        .filter(|d| d.get("doc_ref").and_then(|r| r.as_str()) != Some(doc_ref))
+        .cloned()
+        .collect();
+    if kept.len() == duplicates.len() {
+        return None;
+    }
+    let mut metadata = metadata.clone();
+    metadata["duplicates"] = Value::Array(kept);
+    Some(metadata)
+}
+
+/// `metadata` with `duplicate` appended to its `duplicates`.
+pub fn with_duplicate(metadata: &Value, duplicate: Value) -> Value {
+    let mut metadata = metadata.clone();
+    if let Value::Object(object) = &mut metadata {
+        if let Value::Array(duplicates) = object.entry("duplicates").or_insert(Value::Array(vec![]))
+        {
+            duplicates.push(duplicate);
+        }
+    }
+    metadata
+}
+
+/// Start of a `YYYY-MM-DD` day in unix seconds, UTC.
+pub fn parse_date(date: &str) -> Option<i64> {
+    let mut parts = date.trim().splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // Howard Hinnant's days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some((era * 146097 + day_of_era - 719468) * 86400)
+}
+
+pub struct SearchQuery {
+    pub collection: String,
+    pub embedding: Vec<f32>,
+    // Only vectors made by this model are compared against the query
+    pub model: String,
+    pub filter: Filter,
+    pub limit: usize,
+    // The collection's metric, its index was built for it
+    pub distance: Distance,
+}
+
+/// Where segments and their vectors live. Search results come best first, with `relevence` set to
+/// their similarity to the query.
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    /// Inserts segments, replacing any stored under the same `collection`, `doc_ref` and `segment`.
+    async fn upsert(&self, documents: Vec<DocumentRef>) -> Result<(), StoreError>;
+    async fn delete_doc(&self, collection: &str, doc_ref: &str) -> Result<u64, StoreError>;
+    /// Replaces every segment of a document with `documents` in one go, a failure leaves the
+    /// stored segments as they were.
+    async fn replace_doc(
+        &self,
+        collection: &str,
+        doc_ref: &str,
+        documents: Vec<DocumentRef>,
+    ) -> Result<(), StoreError>;
+    async fn search(&self, query: &SearchQuery) -> Result<Vec<DocumentRef>, StoreError>;
+    /// Full-text search for `text` with the filters and limit of `query`, best first with
+    /// `relevence` set to the text rank. Stores without full-text search find nothing.
+    async fn text_search(
+        &self,
+        _query: &SearchQuery,
+        _text: &str,
+    ) -> Result<Vec<DocumentRef>, StoreError> {
+        Ok(vec![])
+    }
+    /// The given segments of one document, in no particular order, for widening search hits.
+    async fn segments(
+        &self,
+        collection: &str,
+        doc_ref: &str,
+        segments: &[i64],
+    ) -> Result<Vec<DocumentRef>, StoreError>;
+    async fn count(&self) -> Result<i64, StoreError>;
+    /// Number of segments of a collection per embedding model and dimension, `None` where a
+    /// segment doesn't record them.
+    async fn models(
+        &self,
+        collection: &str,
+    ) -> Result<Vec<(Option<String>, Option<i32>, i64)>, StoreError>;
+    /// `doc_ref`, segment number and SimHash of `raw` of every segment of a collection.
+    async fn fingerprints(&self, collection: &str) -> Result<Vec<(String, i64, u64)>, StoreError>;
+    /// Appends a reference to a near-duplicate to the `duplicates` metadata of a segment.
+    async fn add_duplicate(
+        &self,
+        collection: &str,
+        doc_ref: &str,
+        segment: i64,
+        duplicate: Value,
+    ) -> Result<(), StoreError>;
+    /// Removes references to near-duplicates in `doc_ref` from every segment of a collection.
+    async fn remove_duplicates(&self, collection: &str, doc_ref: &str) -> Result<(), StoreError>;
+    async fn cached(&self, keys: &[String]) -> Result<HashMap<String, Vec<f32>>, StoreError>;
+    async fn cache(&self, model: &str, entries: Vec<(String, Vec<f32>)>) -> Result<(), StoreError>;
+
+    /// When and which files of a collection were ingested, `None` when the store leaves that to
+    /// the collection's index file.
+    async fn load_index(
+        &self,
+        _collection: &str,
+    ) -> Result<Option<HashMap<String, u64>>, StoreError> {
+        Ok(None)
+    }
+
+    /// Returns false when the store leaves the index to the collection's index file.
+    async fn save_index(
+        &self,
+        _collection: &str,
+        _index: &HashMap<String, u64>,
+    ) -> Result<bool, StoreError> {
+        Ok(false)
+    }
+
+    /// Messages of a chat session, oldest first. Stores that don't keep history return none.
+    async fn history(&self, _session: &str) -> Result<Vec<OpenAiCompletionMessage>, StoreError> {
+        Ok(vec![])
+    }
+
+    async fn push_history(
+        &self,
+        _session: &str,
+        _message: &OpenAiCompletionMessage,
+    ) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Up to `limit` segments of a collection embedded with another model or dimension than
+    /// `model` makes, for re-embedding them in place. Postgres re-embeds through a staging table
+    /// instead and finds none.
+    async fn stale(
+        &self,
+        _collection: &str,
+        _model: &str,
+        _dimension: usize,
+        _limit: usize,
+    ) -> Result<Vec<DocumentRef>, StoreError> {
+        Ok(vec![])
+    }
+
+    /// Whether segments outlive the process, ingestion starts over when they don't.
+    fn persistent(&self) -> bool {
+        true
+    }
+
+    /// Applies the schema migrations this binary embeds that haven't run yet.
+    async fn migrate(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Fails when the schema is behind the migrations this binary embeds.
+    async fn check_schema(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Readies storage for a collection's vectors of `dimension` compared by `distance`, e.g.
+    /// building a missing nearest neighbour index.
+    async fn prepare(
+        &self,
+        _collection: &str,
+        _dimension: usize,
+        _distance: Distance,
+    ) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// For maintenance jobs that only exist for Postgres.
+    fn postgres(&self) -> Option<&PgStore> {
+        None
+    }
+}
+
+/// Opens the store behind `uri`, `memory://` keeps everything in this process and `sqlite://`
+/// everything in a single file.
+pub async fn connect(uri: &str, vector: &VectorConfig) -> Result<Arc<dyn VectorStore>, StoreError> {
+    if uri.starts_with("memory://") {
+        return Ok(Arc::new(MemoryStore::default()));
+    }
+    if uri.starts_with("sqlite://") {
+        return Ok(Arc::new(SqliteStore::connect(uri).await?));
+    }
+    Ok(Arc::new(PgStore::connect(uri, vector.clone()).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // 2023-01-01, 2023-05-01 and 2023-06-01 in unix seconds
+    const JAN: i64 = 1672531200;
+    const MAY: i64 = 1682899200;
+    const JUNE: i64 = 1685577600;
+
+    fn doc(doc_ref: &str, metadata: Value) -> DocumentRef {
+        DocumentRef {
+            collection: "test_filters".to_string(),
+            embedding: vec![0f32, 1f32],
+            raw: doc_ref.to_string(),
+            doc_ref: doc_ref.to_string(),
+            metadata,
+            embedding_model: Some("test".to_string()),
+            embedding_dim: Some(2),
+            ..DocumentRef::default()
+        }
+    }
+
+    /// Segments covering every filter condition, shared with the Postgres parity test.
+    pub(super) fn corpus() -> Vec<DocumentRef> {
+        vec![
+            doc(
+                "docs/hr/leave.md",
+                json!({ "file_type": "md", "modified": MAY, "department": "hr", "tags": ["policy", "leave"] }),
+            ),
+            doc(
+                "docs/hr/pay.pdf",
+                json!({ "file_type": "pdf", "modified": JAN, "department": "hr" }),
+            ),
+            doc(
+                "docs/eng/runbook.md",
+                json!({ "file_type": "md", "modified": 1700000000, "department": "eng" }),
+            ),
+            doc(
+                "docs/hr_old/notes.txt",
+                json!({ "file_type": "txt", "modified": 1600000000.5 }),
+            ),
+            doc(
+                "docs/hrxold/notes.txt",
+                json!({ "file_type": "txt", "modified": 1500000000 }),
+            ),
+            // Stored before `modified` was reserved, a sidecar replaced it with a string
+            doc(
+                "manuals/printer.pdf",
+                json!({ "file_type": "pdf", "modified": "2023-05-01" }),
+            ),
+            doc(
+                "manuals/ü.pdf",
+                json!({ "file_type": "pdf", "modified": 1690000000 }),
+            ),
+        ]
+    }
+
+    fn filter(value: Value) -> Filter {
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// Filters and the segments of `corpus` each keeps.
+    pub(super) fn filters() -> Vec<(Filter, Vec<&'static str>)> {
+        vec![
+            (
+                Filter::default(),
+                vec![
+                    "docs/hr/leave.md",
+                    "docs/hr/pay.pdf",
+                    "docs/eng/runbook.md",
+                    "docs/hr_old/notes.txt",
+                    "docs/hrxold/notes.txt",
+                    "manuals/printer.pdf",
+                    "manuals/ü.pdf",
+                ],
+            ),
+            (
+                filter(json!({ "doc_ref": "docs/hr/" })),
+                vec!["docs/hr/leave.md", "docs/hr/pay.pdf"],
+            ),
+            // `_` is a literal, not LIKE's wildcard
+            (
+                filter(json!({ "doc_ref": "docs/hr_" })),
+                vec!["docs/hr_old/notes.txt"],
+            ),
+            (
+                filter(json!({ "doc_ref": "manuals/*.pdf" })),
+                vec!["manuals/printer.pdf", "manuals/ü.pdf"],
+            ),
+            (
+                filter(json!({ "doc_ref": "manuals/?.pdf" })),
+                vec!["manuals/ü.pdf"],
+            ),
+            (
+                filter(json!({ "file_type": ["md"] })),
+                vec!["docs/hr/leave.md", "docs/eng/runbook.md"],
+            ),
+            (
+                filter(json!({ "modified_after": "2023-01-01" })),
+                vec![
+                    "docs/hr/leave.md",
+                    "docs/hr/pay.pdf",
+                    "docs/eng/runbook.md",
+                    "manuals/ü.pdf",
+                ],
+            ),
+            (
+                filter(json!({ "modified_before": JAN })),
+                vec!["docs/hr_old/notes.txt", "docs/hrxold/notes.txt"],
+            ),
+            (
+                filter(json!({ "metadata": { "department": "hr" } })),
+                vec!["docs/hr/leave.md", "docs/hr/pay.pdf"],
+            ),
+            (
+                filter(json!({ "metadata": { "tags": ["policy"] } })),
+                vec!["docs/hr/leave.md"],
+            ),
+            (filter(json!({ "metadata": { "tags": "policy" } })), vec![]),
+            (
+                filter(
+                    json!({ "doc_ref": "docs/", "file_type": ["md"], "modified_after": "2023-06-01" }),
+                ),
+                vec!["docs/eng/runbook.md"],
+            ),
+        ]
+    }
+
+    #[test]
+    fn filter_matches_corpus() {
+        let corpus = corpus();
+        for (filter, expected) in filters() {
+            let kept: Vec<&str> = corpus
+                .iter()
+                .filter(|doc| filter.matches(doc))
+                .map(|doc| doc.doc_ref.as_str())
+                .collect();
+            assert_eq!(kept, expected, "{filter:?}");
+        }
+    }
+
+    #[test]
+    fn filter_parses_dates() {
+        let parsed = filter(json!({ "modified_after": "2023-06-01", "modified_before": MAY }));
+        assert_eq!(parsed.modified_after, Some(JUNE));
+        assert_eq!(parsed.modified_before, Some(MAY));
+        assert!(serde_json::from_value::<Filter>(json!({ "modified_after": "June" })).is_err());
+        assert!(serde_json::from_value::<Filter>(json!({ "modified_after": 1.5 })).is_err());
+        assert!(filter(json!({})).is_empty());
+    }
+
+    #[test]
+    fn doc_ref_pattern_escapes_like() {
+        let pattern = |doc_ref: &str| {
+            filter(json!({ "doc_ref": doc_ref }))
+                .doc_ref_pattern()
+                .unwrap()
+        };
+        assert_eq!(pattern("docs/hr/"), "docs/hr/%");
+        assert_eq!(pattern("docs/hr_"), "docs/hr\\_%");
+        assert_eq!(pattern("manuals/*.pdf"), "manuals/%.pdf");
+        assert_eq!(pattern("manuals/?.pdf"), "manuals/_.pdf");
+        assert_eq!(pattern("100%"), "100\\%%");
+        assert_eq!(Filter::default().doc_ref_pattern(), None);
+    }
+
+    #[test]
+    fn glob_matches_whole_path() {
+        let matches = |pattern: &str, text: &str| {
+            let pattern: Vec<char> = pattern.chars().collect();
+            let text: Vec<char> = text.chars().collect();
+            glob_matches(&pattern, &text)
+        };
+        assert!(matches("*.pdf", "manuals/printer.pdf"));
+        assert!(matches("manuals/*", "manuals/"));
+        assert!(matches("a*b*c", "aXbYc"));
+        assert!(matches("?.md", "ü.md"));
+        assert!(!matches("?.md", "ab.md"));
+        assert!(!matches("*.pdf", "manuals/printer.pdf.md"));
+        assert!(!matches("docs/", "docs/a.md"));
+    }
+
+    #[test]
+    fn parse_date_is_utc_midnight() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(parse_date("2023-01-01"), Some(JAN));
+        assert_eq!(parse_date(" 2023-05-01 "), Some(MAY));
+        // Leap day of a century divisible by 400
+        assert_eq!(parse_date("2000-03-01"), Some(951868800));
+        assert_eq!(parse_date("1969-12-31"), Some(-86400));
+        assert_eq!(parse_date("2023-13-01"), None);
+        assert_eq!(parse_date("2023-05"), None);
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn json_contains_like_postgres() {
+        let metadata = json!({ "a": 1, "tags": ["x", "y"], "nested": { "b": [1, [2, 3]] } });
+        assert!(json_contains(&metadata, &json!({})));
+        assert!(json_contains(&metadata, &json!({ "a": 1 })));
+        assert!(json_contains(&metadata, &json!({ "tags": ["y"] })));
+        assert!(json_contains(
+            &metadata,
+            &json!({ "nested": { "b": [[3]] } })
+        ));
+        assert!(!json_contains(&metadata, &json!({ "a": "1" })));
+        assert!(!json_contains(&metadata, &json!({ "tags": "x" })));
+        assert!(!json_contains(&metadata, &json!({ "tags": ["z"] })));
+        assert!(!json_contains(&metadata, &json!({ "missing": null })));
+    }
+
+    #[test]
+    fn by_relevence_ranks_missing_and_nan_last() {
+        let scored = |relevence: Option<f32>| DocumentRef {
+            relevence,
+            ..DocumentRef::default()
+        };
+        let mut found = vec![
+            scored(Some(f32::NAN)),
+            scored(Some(0.2)),
+            scored(None),
+            scored(Some(0.9)),
+        ];
+        found.sort_by(by_relevence);
+        assert_eq!(found[0].relevence, Some(0.9));
+        assert_eq!(found[1].relevence, Some(0.2));
+        assert!(found[2..]
+            .iter()
+            .all(|d| d.relevence.is_none_or(f32::is_nan)));
+    }
+
+    #[test]
+    fn duplicates_are_added_and_removed_by_source() {
+        let metadata = json!({ "file_name": "a.md" });
+        let metadata = with_duplicate(&metadata, json!({ "doc_ref": "b.md", "segment": 1 }));
+        let metadata = with_duplicate(&metadata, json!({ "doc_ref": "c.md", "segment": 2 }));
+        assert_eq!(metadata["duplicates"].as_array().unwrap().len(), 2);
+        let without = without_duplicates_of(&metadata, "b.md").unwrap();
+        assert_eq!(
+            without["duplicates"],
+            json!([{ "doc_ref": "c.md", "segment": 2 }])
+        );
+        assert_eq!(without["file_name"], "a.md");
+        assert_eq!(without_duplicates_of(&without, "b.md"), None);
+        assert_eq!(without_duplicates_of(&json!({}), "b.md"), None);
+    }
+}
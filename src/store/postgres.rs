@@ -0,0 +1,581 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use serde_json::Value;
+use sqlx::{
+    migrate::Migrator,
+    postgres::{PgArguments, PgPoolOptions},
+    query::QueryAs,
+    Pool, Postgres, Transaction,
+};
+
+use crate::{
+    cli::{VectorConfig, VectorExtension, VectorIndex},
+    dedup::{fingerprint, simhash},
+    schemas::DocumentRef,
+};
+
+use super::{Distance, Filter, SearchQuery, StoreError, VectorStore};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+// pgvector's `vector` type has no sqlx mapping, so it travels as `real[]`
+const PGVECTOR_COLUMNS: &str = "collection, embedding::real[] AS embedding, raw, doc_ref, segment, start_offset, end_offset, start_line, end_line, metadata, embedding_model, embedding_dim";
+
+// Conditions of a `Filter` bound from $2, unset ones are NULL and always hold
+// Rows stored before `modified` was reserved may have anything there, the CASE keeps the cast from
+// seeing a non-number and leaves such rows out like `Filter::matches` does
+const FILTER_CONDITIONS: &str = "metadata @> $2 AND ($5::text IS NULL OR doc_ref LIKE $5) AND ($6::text[] IS NULL OR metadata->>'file_type' = ANY($6)) AND ($7::bigint IS NULL OR (CASE WHEN jsonb_typeof(metadata->'modified') = 'number' THEN (metadata->>'modified')::numeric END) >= $7) AND ($8::bigint IS NULL OR (CASE WHEN jsonb_typeof(metadata->'modified') = 'number' THEN (metadata->>'modified')::numeric END) < $8)";
+
+// Rows a collection's index covers. Inlined rather than bound, a partial index is only used
+// when the planner can see the query asks for the same rows.
+fn scope(collection: &str, dimension: usize) -> String {
+    format!(
+        "collection = '{}' AND embedding_dim = {dimension}",
+        collection.replace('\'', "''")
+    )
+}
+
+fn bind_filter<'q>(
+    query: QueryAs<'q, Postgres, DocumentRef, PgArguments>,
+    filter: &Filter,
+    model: &'q str,
+    limit: usize,
+) -> QueryAs<'q, Postgres, DocumentRef, PgArguments> {
+    query
+        .bind(Value::Object(filter.metadata.clone()))
+        .bind(model)
+        .bind(limit as i64)
+        .bind(filter.doc_ref_pattern())
+        .bind(filter.file_type.clone())
+        .bind(filter.modified_after)
+        .bind(filter.modified_before)
+}
+
+pub struct PgStore {
+    pool: Pool<Postgres>,
+    extension: VectorExtension,
+    config: VectorConfig,
+}
+
+impl PgStore {
+    pub async fn connect(uri: &str, config: VectorConfig) -> Result<Self, StoreError> {
+        let pool = PgPoolOptions::new()
+            .max_connections(10)
+            .connect(uri)
+            .await?;
+        let installed: Vec<String> = sqlx::query_scalar(
+            "SELECT extname::text FROM pg_extension WHERE extname IN ('vector', 'embedding')",
+        )
+        .fetch_all(&pool)
+        .await?;
+        let pgvector = installed.iter().any(|e| e == "vector");
+        let pg_embedding = installed.iter().any(|e| e == "embedding");
+        let extension =
+            match &config.extension {
+                Some(VectorExtension::PgVector) if !pgvector => {
+                    return Err("pgvector is not installed, run `CREATE EXTENSION vector`".into())
+                }
+                Some(VectorExtension::PgEmbedding) if !pg_embedding => {
+                    return Err(
+                        "pg_embedding is not installed, run `CREATE EXTENSION embedding`".into(),
+                    )
+                }
+                Some(extension) => extension.clone(),
+                None if pgvector => VectorExtension::PgVector,
+                None if pg_embedding => VectorExtension::PgEmbedding,
+                None => return Err(
+                    "Neither pgvector nor pg_embedding is installed, run `CREATE EXTENSION vector`"
+                        .into(),
+                ),
+            };
+        if extension == VectorExtension::PgEmbedding && config.index == VectorIndex::IvfFlat {
+            return Err("pg_embedding only has HNSW indexes, use pgvector for IVFFlat".into());
+        }
+        Ok(PgStore {
+            pool,
+            extension,
+            config,
+        })
+    }
+
+    pub fn pool(&self) -> &Pool<Postgres> {
+        &self.pool
+    }
+
+    pub fn extension(&self) -> &VectorExtension {
+        &self.extension
+    }
+
+    fn operator(distance: Distance) -> &'static str {
+        match distance {
+            Distance::Euclidean => "<->",
+            Distance::Cosine => "<=>",
+            Distance::Dot => "<#>",
+        }
+    }
+
+    fn columns(&self) -> &'static str {
+        match self.extension {
+            VectorExtension::PgVector => PGVECTOR_COLUMNS,
+            VectorExtension::PgEmbedding => "documents.*",
+        }
+    }
+
+    // Vectors are bound as `real[]` and cast where the column is pgvector's
+    fn vector_param(&self, n: usize) -> String {
+        match self.extension {
+            VectorExtension::PgVector => format!("${n}::real[]::vector"),
+            VectorExtension::PgEmbedding => format!("${n}"),
+        }
+    }
+
+    // pgvector's column holds vectors of every collection, so it has no dimension. Indexes and
+    // searches cast to the collection's, and have to use the same expression for the index to apply.
+    fn with_dimension(&self, expression: &str, dimension: usize) -> String {
+        match self.extension {
+            VectorExtension::PgVector => format!("({expression})::vector({dimension})"),
+            VectorExtension::PgEmbedding => expression.to_string(),
+        }
+    }
+
+    /// Name of the nearest neighbour index of a collection.
+    pub fn index_name(collection: &str) -> String {
+        format!("documents_embedding_{collection}_idx")
+    }
+
+    // Operator class an index needs to serve searches by `distance`
+    fn operator_class(&self, distance: Distance) -> &'static str {
+        match (&self.extension, distance) {
+            (VectorExtension::PgEmbedding, Distance::Cosine) => "ann_cos_ops",
+            (VectorExtension::PgEmbedding, _) => "ann_l2_ops",
+            (VectorExtension::PgVector, Distance::Euclidean) => "vector_l2_ops",
+            (VectorExtension::PgVector, Distance::Cosine) => "vector_cosine_ops",
+            (VectorExtension::PgVector, Distance::Dot) => "vector_ip_ops",
+        }
+    }
+
+    /// `CREATE INDEX` over a collection's vectors of `dimension` for the configured extension
+    /// and index type, and the collection's distance.
+    pub fn index_statement(
+        &self,
+        collection: &str,
+        dimension: usize,
+        distance: Distance,
+    ) -> String {
+        let name = Self::index_name(collection);
+        let scope = scope(collection, dimension);
+        let ops = self.operator_class(distance);
+        match (&self.extension, &self.config.index) {
+            (VectorExtension::PgEmbedding, _) => {
+                format!(
+                    "CREATE INDEX IF NOT EXISTS {name} ON documents USING hnsw(embedding {ops}) WITH (dims={dimension}) WHERE {scope}"
+                )
+            }
+            (VectorExtension::PgVector, index) => {
+                let embedding = self.with_dimension("embedding", dimension);
+                match index {
+                    VectorIndex::Hnsw => format!(
+                        "CREATE INDEX IF NOT EXISTS {name} ON documents USING hnsw(({embedding}) {ops}) WHERE {scope}"
+                    ),
+                    VectorIndex::IvfFlat => format!(
+                        "CREATE INDEX IF NOT EXISTS {name} ON documents USING ivfflat(({embedding}) {ops}) WITH (lists={}) WHERE {scope}",
+                        self.config.lists
+                    ),
+                }
+            }
+        }
+    }
+
+    // Query time knobs of the pgvector index, scoped to the search transaction
+    fn search_setting(&self) -> Option<String> {
+        if self.extension != VectorExtension::PgVector {
+            return None;
+        }
+        match self.config.index {
+            VectorIndex::Hnsw => self
+                .config
+                .ef_search
+                .map(|ef_search| format!("SET LOCAL hnsw.ef_search = {ef_search}")),
+            VectorIndex::IvfFlat => self
+                .config
+                .probes
+                .map(|probes| format!("SET LOCAL ivfflat.probes = {probes}")),
+        }
+    }
+
+    /// Inserts segments as part of `transaction`, replacing any stored under the same key.
+    async fn insert(
+        &self,
+        transaction: &mut Transaction<'_, Postgres>,
+        documents: Vec<DocumentRef>,
+    ) -> Result<(), StoreError> {
+        for doc in documents {
+            sqlx::query(
+                "DELETE FROM documents WHERE collection = $1 AND doc_ref = $2 AND segment = $3",
+            )
+            .bind(&doc.collection)
+            .bind(&doc.doc_ref)
+            .bind(doc.segment)
+            .execute(&mut **transaction)
+            .await?;
+            let hash = simhash(&doc.raw) as i64;
+            sqlx::query(&format!(
+                "INSERT INTO documents (embedding, raw, doc_ref, segment, start_offset, end_offset, start_line, end_line, metadata, embedding_model, embedding_dim, collection, simhash) VALUES ({}, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)",
+                self.vector_param(1)
+            ))
+            .bind(doc.embedding)
+            .bind(doc.raw)
+            .bind(doc.doc_ref)
+            .bind(doc.segment)
+            .bind(doc.start_offset)
+            .bind(doc.end_offset)
+            .bind(doc.start_line)
+            .bind(doc.end_line)
+            .bind(doc.metadata)
+            .bind(doc.embedding_model)
+            .bind(doc.embedding_dim)
+            .bind(doc.collection)
+            .bind(hash)
+            .execute(&mut **transaction)
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl VectorStore for PgStore {
+    async fn upsert(&self, documents: Vec<DocumentRef>) -> Result<(), StoreError> {
+        let mut transaction = self.pool.begin().await?;
+        self.insert(&mut transaction, documents).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn replace_doc(
+        &self,
+        collection: &str,
+        doc_ref: &str,
+        documents: Vec<DocumentRef>,
+    ) -> Result<(), StoreError> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query("DELETE FROM documents WHERE collection = $1 AND doc_ref = $2")
+            .bind(collection)
+            .bind(doc_ref)
+            .execute(&mut *transaction)
+            .await?;
+        self.insert(&mut transaction, documents).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn delete_doc(&self, collection: &str, doc_ref: &str) -> Result<u64, StoreError> {
+        Ok(
+            sqlx::query("DELETE FROM documents WHERE collection = $1 AND doc_ref = $2")
+                .bind(collection)
+                .bind(doc_ref)
+                .execute(&self.pool)
+                .await?
+                .rows_affected(),
+        )
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<Vec<DocumentRef>, StoreError> {
+        let mut transaction = self.pool.begin().await?;
+        if let Some(setting) = self.search_setting() {
+            sqlx::query(&setting).execute(&mut *transaction).await?;
+        }
+        // Ordered by the bare operator so the index is used, pgvector's distances are doubles
+        let dimension = query.embedding.len();
+        let distance = format!(
+            "{} {} {}",
+            self.with_dimension("embedding", dimension),
+            Self::operator(query.distance),
+            self.with_dimension(&self.vector_param(1), dimension)
+        );
+        let sql = format!(
+            "SELECT {}, ({distance})::real as relevence FROM documents WHERE {} AND {FILTER_CONDITIONS} AND embedding_model = $3 ORDER BY {distance} LIMIT $4",
+            self.columns(),
+            scope(&query.collection, dimension),
+        );
+        let found = bind_filter(
+            sqlx::query_as::<_, DocumentRef>(&sql).bind(&query.embedding),
+            &query.filter,
+            &query.model,
+            query.limit,
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(found
+            .into_iter()
+            .map(|doc| DocumentRef {
+                relevence: doc.relevence.map(|d| query.distance.similarity(d)),
+                ..doc
+            })
+            .collect())
+    }
+
+    async fn text_search(
+        &self,
+        query: &SearchQuery,
+        text: &str,
+    ) -> Result<Vec<DocumentRef>, StoreError> {
+        // Any of the question's terms can match, ranked by how many and how close together
+        let sql = format!(
+            "SELECT {}, ts_rank_cd(raw_tsv, terms)::real as relevence FROM documents, to_tsquery('english', replace(plainto_tsquery('english', $1)::text, '&', '|')) terms WHERE raw_tsv @@ terms AND {} AND {FILTER_CONDITIONS} AND embedding_model = $3 ORDER BY relevence DESC LIMIT $4",
+            self.columns(),
+            scope(&query.collection, query.embedding.len()),
+        );
+        Ok(bind_filter(
+            sqlx::query_as::<_, DocumentRef>(&sql).bind(text),
+            &query.filter,
+            &query.model,
+            query.limit,
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn segments(
+        &self,
+        collection: &str,
+        doc_ref: &str,
+        segments: &[i64],
+    ) -> Result<Vec<DocumentRef>, StoreError> {
+        Ok(sqlx::query_as::<_, DocumentRef>(&format!(
+            "SELECT {}, NULL::real as relevence FROM documents WHERE collection = $3 AND doc_ref = $1 AND segment = ANY($2)",
+            self.columns()
+        ))
+        .bind(doc_ref)
+        .bind(segments)
+        .bind(collection)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn count(&self) -> Result<i64, StoreError> {
+        Ok(sqlx::query_scalar("SELECT count(*) FROM documents")
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn models(
+        &self,
+        collection: &str,
+    ) -> Result<Vec<(Option<String>, Option<i32>, i64)>, StoreError> {
+        Ok(sqlx::query_as(
+            "SELECT embedding_model, embedding_dim, count(*) FROM documents WHERE collection = $1 GROUP BY embedding_model, embedding_dim",
+        )
+        .bind(collection)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn fingerprints(&self, collection: &str) -> Result<Vec<(String, i64, u64)>, StoreError> {
+        let rows: Vec<(String, i64, Option<i64>, Option<String>)> = sqlx::query_as(
+            "SELECT doc_ref, segment, simhash, CASE WHEN simhash IS NULL THEN raw END FROM documents WHERE collection = $1",
+        )
+        .bind(collection)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(doc_ref, segment, hash, raw)| {
+                (doc_ref, segment, fingerprint(hash, raw.as_deref()))
+            })
+            .collect())
+    }
+
+    async fn add_duplicate(
+        &self,
+        collection: &str,
+        doc_ref: &str,
+        segment: i64,
+        duplicate: Value,
+    ) -> Result<(), StoreError> {
+        sqlx::query(
+            "UPDATE documents SET metadata = jsonb_set(metadata, '{duplicates}', coalesce(metadata->'duplicates', '[]'::jsonb) || jsonb_build_array($4::jsonb)) WHERE collection = $1 AND doc_ref = $2 AND segment = $3",
+        )
+        .bind(collection)
+        .bind(doc_ref)
+        .bind(segment)
+        .bind(duplicate)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_duplicates(&self, collection: &str, doc_ref: &str) -> Result<(), StoreError> {
+        sqlx::query(
+            "UPDATE documents SET metadata = jsonb_set(metadata, '{duplicates}', (SELECT coalesce(jsonb_agg(d), '[]'::jsonb) FROM jsonb_array_elements(metadata->'duplicates') d WHERE d->>'doc_ref' <> $2)) WHERE collection = $1 AND metadata @> jsonb_build_object('duplicates', jsonb_build_array(jsonb_build_object('doc_ref', $2::text)))",
+        )
+        .bind(collection)
+        .bind(doc_ref)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn cached(&self, keys: &[String]) -> Result<HashMap<String, Vec<f32>>, StoreError> {
+        let rows: Vec<(String, Vec<f32>)> =
+            sqlx::query_as("SELECT key, embedding FROM embedding_cache WHERE key = ANY($1)")
+                .bind(keys)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows.into_iter().collect())
+    }
+
+    async fn cache(&self, model: &str, entries: Vec<(String, Vec<f32>)>) -> Result<(), StoreError> {
+        for (key, embedding) in entries {
+            sqlx::query(
+                "INSERT INTO embedding_cache (key, embedding_model, embedding) VALUES ($1, $2, $3) ON CONFLICT (key) DO NOTHING",
+            )
+            .bind(key)
+            .bind(model)
+            .bind(embedding)
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn migrate(&self) -> Result<(), StoreError> {
+        MIGRATOR.run(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn check_schema(&self) -> Result<(), StoreError> {
+        let expected = MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0);
+        let tracked: bool =
+            sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+                .fetch_one(&self.pool)
+                .await?;
+        let applied: Option<i64> = if tracked {
+            sqlx::query_scalar("SELECT max(version) FROM _sqlx_migrations WHERE success")
+                .fetch_one(&self.pool)
+                .await?
+        } else {
+            None
+        };
+        match applied {
+            Some(applied) if applied >= expected => Ok(()),
+            applied => Err(format!(
+                "Schema is at version {}, expected {expected}, run `llm-chatbot migrate` or set AUTO_MIGRATE=true",
+                applied.unwrap_or(0)
+            )
+            .into()),
+        }
+    }
+
+    async fn prepare(
+        &self,
+        collection: &str,
+        dimension: usize,
+        distance: Distance,
+    ) -> Result<(), StoreError> {
+        if self.extension == VectorExtension::PgEmbedding && distance == Distance::Dot {
+            return Err("pg_embedding has no inner product distance, use pgvector".into());
+        }
+        // Migrations create a plain `real[]` column and earlier versions a `vector(n)` one,
+        // collections of different dimensions need pgvector's column without one
+        if self.extension == VectorExtension::PgVector {
+            let column: String = sqlx::query_scalar(
+                "SELECT format_type(atttypid, atttypmod) FROM pg_attribute WHERE attrelid = 'documents'::regclass AND attname = 'embedding'",
+            )
+            .fetch_one(&self.pool)
+            .await?;
+            if column != "vector" {
+                sqlx::query(
+                    "ALTER TABLE documents ALTER COLUMN embedding TYPE vector USING embedding::vector",
+                )
+                .execute(&self.pool)
+                .await?;
+            }
+        }
+        // An index built for another distance is never used by searches, so it is rebuilt
+        let name = Self::index_name(collection);
+        let existing: Option<String> =
+            sqlx::query_scalar("SELECT indexdef FROM pg_indexes WHERE indexname = $1")
+                .bind(&name)
+                .fetch_optional(&self.pool)
+                .await?;
+        if existing.is_some_and(|definition| !definition.contains(self.operator_class(distance))) {
+            println!("Rebuilding {name} for {distance:?} distance");
+            sqlx::query(&format!("DROP INDEX {name}"))
+                .execute(&self.pool)
+                .await?;
+        }
+        sqlx::query(&self.index_statement(collection, dimension, distance))
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    fn postgres(&self) -> Option<&PgStore> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::{corpus, filters};
+
+    // Needs a scratch database with pgvector, e.g. `TEST_PG_URI=postgres://localhost/lambot_test`,
+    // and passes trivially without one
+    #[tokio::test]
+    async fn filter_conditions_agree_with_filter_matches() {
+        let Ok(uri) = std::env::var("TEST_PG_URI") else {
+            return;
+        };
+        let store = PgStore::connect(&uri, VectorConfig::default())
+            .await
+            .unwrap();
+        store.migrate().await.unwrap();
+        store
+            .prepare("test_filters", 2, Distance::Euclidean)
+            .await
+            .unwrap();
+        let corpus = corpus();
+        for doc in &corpus {
+            store
+                .delete_doc("test_filters", &doc.doc_ref)
+                .await
+                .unwrap();
+        }
+        store.upsert(corpus.clone()).await.unwrap();
+        for (filter, _) in filters() {
+            let query = SearchQuery {
+                collection: "test_filters".to_string(),
+                embedding: vec![0f32, 1f32],
+                model: "test".to_string(),
+                filter: filter.clone(),
+                limit: 100,
+                distance: Distance::Euclidean,
+            };
+            let mut found: Vec<String> = store
+                .search(&query)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|doc| doc.doc_ref)
+                .collect();
+            found.sort();
+            let mut expected: Vec<String> = corpus
+                .iter()
+                .filter(|doc| filter.matches(doc))
+                .map(|doc| doc.doc_ref.clone())
+                .collect();
+            expected.sort();
+            assert_eq!(found, expected, "{filter:?}");
+        }
+        for doc in &corpus {
+            store
+                .delete_doc("test_filters", &doc.doc_ref)
+                .await
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,427 @@
+use std::{collections::HashMap, str::FromStr};
+
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteRow},
+    Pool, Row, Sqlite, Transaction,
+};
+
+use serde_json::Value;
+
+use crate::{
+    dedup::{fingerprint, simhash},
+    schemas::{DocumentRef, OpenAiCompletionMessage},
+};
+
+use super::{
+    by_relevence, with_duplicate, without_duplicates_of, SearchQuery, StoreError, VectorStore,
+};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// Everything in one SQLite file, searched by brute force. Meant for laptops and small installs
+/// where running Postgres is too much.
+pub struct SqliteStore {
+    pool: Pool<Sqlite>,
+}
+
+impl SqliteStore {
+    pub async fn connect(uri: &str) -> Result<Self, StoreError> {
+        let options = SqliteConnectOptions::from_str(uri)?.create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(4)
+            .connect_with(options)
+            .await?;
+        Ok(SqliteStore { pool })
+    }
+
+    /// Inserts segments as part of `transaction`, replacing any stored under the same key.
+    async fn insert(
+        &self,
+        transaction: &mut Transaction<'_, Sqlite>,
+        documents: Vec<DocumentRef>,
+    ) -> Result<(), StoreError> {
+        for doc in documents {
+            sqlx::query(
+                "DELETE FROM documents WHERE collection = ? AND doc_ref = ? AND segment = ?",
+            )
+            .bind(&doc.collection)
+            .bind(&doc.doc_ref)
+            .bind(doc.segment)
+            .execute(&mut **transaction)
+            .await?;
+            let hash = simhash(&doc.raw) as i64;
+            sqlx::query(
+                "INSERT INTO documents (collection, embedding, raw, doc_ref, segment, start_offset, end_offset, start_line, end_line, metadata, embedding_model, embedding_dim, simhash) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(doc.collection)
+            .bind(to_blob(&doc.embedding))
+            .bind(doc.raw)
+            .bind(doc.doc_ref)
+            .bind(doc.segment)
+            .bind(doc.start_offset)
+            .bind(doc.end_offset)
+            .bind(doc.start_line)
+            .bind(doc.end_line)
+            .bind(doc.metadata.to_string())
+            .bind(doc.embedding_model)
+            .bind(doc.embedding_dim)
+            .bind(hash)
+            .execute(&mut **transaction)
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+// Vectors are stored as little endian f32s
+fn to_blob(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+fn document_from_row(row: &SqliteRow) -> Result<DocumentRef, StoreError> {
+    let metadata: String = row.try_get("metadata")?;
+    Ok(DocumentRef {
+        collection: row.try_get("collection")?,
+        embedding: from_blob(row.try_get::<&[u8], _>("embedding")?),
+        raw: row.try_get("raw")?,
+        relevence: None,
+        doc_ref: row.try_get("doc_ref")?,
+        segment: row.try_get("segment")?,
+        start_offset: row.try_get("start_offset")?,
+        end_offset: row.try_get("end_offset")?,
+        start_line: row.try_get("start_line")?,
+        end_line: row.try_get("end_line")?,
+        metadata: serde_json::from_str(&metadata)?,
+        embedding_model: row.try_get("embedding_model")?,
+        embedding_dim: row.try_get("embedding_dim")?,
+    })
+}
+
+#[async_trait]
+impl VectorStore for SqliteStore {
+    async fn upsert(&self, documents: Vec<DocumentRef>) -> Result<(), StoreError> {
+        let mut transaction = self.pool.begin().await?;
+        self.insert(&mut transaction, documents).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn replace_doc(
+        &self,
+        collection: &str,
+        doc_ref: &str,
+        documents: Vec<DocumentRef>,
+    ) -> Result<(), StoreError> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query("DELETE FROM documents WHERE collection = ? AND doc_ref = ?")
+            .bind(collection)
+            .bind(doc_ref)
+            .execute(&mut *transaction)
+            .await?;
+        self.insert(&mut transaction, documents).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn delete_doc(&self, collection: &str, doc_ref: &str) -> Result<u64, StoreError> {
+        Ok(
+            sqlx::query("DELETE FROM documents WHERE collection = ? AND doc_ref = ?")
+                .bind(collection)
+                .bind(doc_ref)
+                .execute(&self.pool)
+                .await?
+                .rows_affected(),
+        )
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<Vec<DocumentRef>, StoreError> {
+        let mut found: Vec<DocumentRef> = Vec::with_capacity(query.limit + 1);
+        // Streamed so only the best `limit` segments are held at any time
+        // Vectors of another dimension can't be compared, like on Postgres they aren't searched
+        let mut rows = sqlx::query(
+            "SELECT * FROM documents WHERE collection = ? AND embedding_model = ? AND embedding_dim = ?",
+        )
+        .bind(&query.collection)
+        .bind(&query.model)
+        .bind(query.embedding.len() as i64)
+        .fetch(&self.pool);
+        while let Some(row) = rows.try_next().await? {
+            let doc = document_from_row(&row)?;
+            if !query.filter.matches(&doc) {
+                continue;
+            }
+            let similarity = query
+                .distance
+                .similarity(query.distance.between(&doc.embedding, &query.embedding));
+            let doc = DocumentRef {
+                relevence: Some(similarity),
+                ..doc
+            };
+            let position = found.partition_point(|d| by_relevence(d, &doc).is_le());
+            if position >= query.limit {
+                continue;
+            }
+            found.insert(position, doc);
+            found.truncate(query.limit);
+        }
+        Ok(found)
+    }
+
+    async fn segments(
+        &self,
+        collection: &str,
+        doc_ref: &str,
+        segments: &[i64],
+    ) -> Result<Vec<DocumentRef>, StoreError> {
+        let mut found = Vec::new();
+        for segment in segments {
+            let rows = sqlx::query(
+                "SELECT * FROM documents WHERE collection = ? AND doc_ref = ? AND segment = ?",
+            )
+            .bind(collection)
+            .bind(doc_ref)
+            .bind(segment)
+            .fetch_all(&self.pool)
+            .await?;
+            for row in &rows {
+                found.push(document_from_row(row)?);
+            }
+        }
+        Ok(found)
+    }
+
+    async fn count(&self) -> Result<i64, StoreError> {
+        Ok(sqlx::query_scalar("SELECT count(*) FROM documents")
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn models(
+        &self,
+        collection: &str,
+    ) -> Result<Vec<(Option<String>, Option<i32>, i64)>, StoreError> {
+        Ok(sqlx::query_as(
+            "SELECT embedding_model, embedding_dim, count(*) FROM documents WHERE collection = ? GROUP BY embedding_model, embedding_dim",
+        )
+        .bind(collection)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn fingerprints(&self, collection: &str) -> Result<Vec<(String, i64, u64)>, StoreError> {
+        let rows: Vec<(String, i64, Option<i64>, Option<String>)> = sqlx::query_as(
+            "SELECT doc_ref, segment, simhash, CASE WHEN simhash IS NULL THEN raw END FROM documents WHERE collection = ?",
+        )
+        .bind(collection)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(doc_ref, segment, hash, raw)| {
+                (doc_ref, segment, fingerprint(hash, raw.as_deref()))
+            })
+            .collect())
+    }
+
+    async fn add_duplicate(
+        &self,
+        collection: &str,
+        doc_ref: &str,
+        segment: i64,
+        duplicate: Value,
+    ) -> Result<(), StoreError> {
+        let mut transaction = self.pool.begin().await?;
+        let metadata: Option<String> = sqlx::query_scalar(
+            "SELECT metadata FROM documents WHERE collection = ? AND doc_ref = ? AND segment = ?",
+        )
+        .bind(collection)
+        .bind(doc_ref)
+        .bind(segment)
+        .fetch_optional(&mut *transaction)
+        .await?;
+        if let Some(metadata) = metadata {
+            let metadata = with_duplicate(&serde_json::from_str(&metadata)?, duplicate);
+            sqlx::query(
+                "UPDATE documents SET metadata = ? WHERE collection = ? AND doc_ref = ? AND segment = ?",
+            )
+            .bind(metadata.to_string())
+            .bind(collection)
+            .bind(doc_ref)
+            .bind(segment)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn remove_duplicates(&self, collection: &str, doc_ref: &str) -> Result<(), StoreError> {
+        let mut transaction = self.pool.begin().await?;
+        let rows: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT id, metadata FROM documents WHERE collection = ? AND metadata LIKE '%\"duplicates\"%'",
+        )
+        .bind(collection)
+        .fetch_all(&mut *transaction)
+        .await?;
+        for (id, metadata) in rows {
+            if let Some(metadata) =
+                without_duplicates_of(&serde_json::from_str(&metadata)?, doc_ref)
+            {
+                sqlx::query("UPDATE documents SET metadata = ? WHERE id = ?")
+                    .bind(metadata.to_string())
+                    .bind(id)
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn cached(&self, keys: &[String]) -> Result<HashMap<String, Vec<f32>>, StoreError> {
+        let mut found = HashMap::new();
+        for key in keys {
+            let embedding: Option<Vec<u8>> =
+                sqlx::query_scalar("SELECT embedding FROM embedding_cache WHERE key = ?")
+                    .bind(key)
+                    .fetch_optional(&self.pool)
+                    .await?;
+            if let Some(embedding) = embedding {
+                found.insert(key.clone(), from_blob(&embedding));
+            }
+        }
+        Ok(found)
+    }
+
+    async fn cache(&self, model: &str, entries: Vec<(String, Vec<f32>)>) -> Result<(), StoreError> {
+        for (key, embedding) in entries {
+            sqlx::query(
+                "INSERT OR IGNORE INTO embedding_cache (key, embedding_model, embedding) VALUES (?, ?, ?)",
+            )
+            .bind(key)
+            .bind(model)
+            .bind(to_blob(&embedding))
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn stale(
+        &self,
+        collection: &str,
+        model: &str,
+        dimension: usize,
+        limit: usize,
+    ) -> Result<Vec<DocumentRef>, StoreError> {
+        let rows = sqlx::query(
+            "SELECT * FROM documents WHERE collection = ? AND (embedding_model IS NOT ? OR embedding_dim IS NOT ?) LIMIT ?",
+        )
+        .bind(collection)
+        .bind(model)
+        .bind(dimension as i64)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter().map(document_from_row).collect()
+    }
+
+    async fn load_index(
+        &self,
+        collection: &str,
+    ) -> Result<Option<HashMap<String, u64>>, StoreError> {
+        let rows: Vec<(String, i64)> =
+            sqlx::query_as("SELECT path, modified FROM ingest_index WHERE collection = ?")
+                .bind(collection)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(Some(
+            rows.into_iter()
+                .map(|(path, modified)| (path, modified as u64))
+                .collect(),
+        ))
+    }
+
+    async fn save_index(
+        &self,
+        collection: &str,
+        index: &HashMap<String, u64>,
+    ) -> Result<bool, StoreError> {
+        let mut transaction = self.pool.begin().await?;
+        for (path, modified) in index {
+            sqlx::query(
+                "INSERT OR REPLACE INTO ingest_index (collection, path, modified) VALUES (?, ?, ?)",
+            )
+            .bind(collection)
+            .bind(path)
+            .bind(*modified as i64)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(true)
+    }
+
+    async fn history(&self, session: &str) -> Result<Vec<OpenAiCompletionMessage>, StoreError> {
+        let messages: Vec<String> =
+            sqlx::query_scalar("SELECT message FROM chat_history WHERE session = ? ORDER BY id")
+                .bind(session)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(messages
+            .iter()
+            .map(|message| serde_json::from_str(message))
+            .collect::<Result<_, _>>()?)
+    }
+
+    async fn push_history(
+        &self,
+        session: &str,
+        message: &OpenAiCompletionMessage,
+    ) -> Result<(), StoreError> {
+        sqlx::query("INSERT INTO chat_history (session, message) VALUES (?, ?)")
+            .bind(session)
+            .bind(serde_json::to_string(message)?)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn migrate(&self) -> Result<(), StoreError> {
+        MIGRATOR.run(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn check_schema(&self) -> Result<(), StoreError> {
+        let expected = MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0);
+        let tracked: bool = sqlx::query_scalar(
+            "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        let applied: Option<i64> = if tracked {
+            sqlx::query_scalar("SELECT max(version) FROM _sqlx_migrations WHERE success")
+                .fetch_one(&self.pool)
+                .await?
+        } else {
+            None
+        };
+        match applied {
+            Some(applied) if applied >= expected => Ok(()),
+            applied => Err(format!(
+                "Schema is at version {}, expected {expected}, run `llm-chatbot migrate` or set AUTO_MIGRATE=true",
+                applied.unwrap_or(0)
+            )
+            .into()),
+        }
+    }
+}
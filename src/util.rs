@@ -1,27 +1,37 @@
-use crate::{cli::Config, schemas::DocumentRef};
+use crate::{
+    cache::EmbeddingCache,
+    cli::{CollectionConfig, DedupMode, EmbeddingBackend, EmbeddingConfig, SplitterConfig},
+    dedup::{dedupe, referenced_docs, Deduplicator, IngestStats},
+    embedder::create_embedder,
+    schemas::DocumentRef,
+    splitter::{Chunk, LineIndex, SemanticSplitter},
+    store::{StoreError, VectorStore},
+};
 use poppler::PopplerDocument;
 use reqwest::{multipart, Client};
-use serde_json::Value;
+use serde_json::{Map, Value};
 use std::{
     collections::HashMap,
     error::Error,
     fs::{self, DirEntry},
     io::{BufReader, BufWriter, Read},
-    path::PathBuf,
-    sync::{Arc, Mutex},
-    time::{SystemTime, UNIX_EPOCH},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::{cli::parse_args, schemas::EncodingRequest, TextSplitter};
-use futures_util::future::join_all;
-use rust_bert::pipelines::sentence_embeddings::{
-    SentenceEmbeddingsBuilder, SentenceEmbeddingsModelType,
+use crate::{
+    cli::parse_args,
+    schemas::{EncodingReceiver, EncodingRequest, EncodingSender, WorkerStatus},
+    TextSplitter,
 };
-use sqlx::{Pool, Postgres};
+use futures_util::future::join_all;
 use tokio::{
+    runtime::Handle,
     spawn,
     sync::{
-        mpsc::{UnboundedReceiver, UnboundedSender},
+        mpsc::{self, error::TryRecvError, UnboundedReceiver, UnboundedSender},
         oneshot,
     },
     task::{spawn_blocking, JoinHandle},
@@ -29,69 +39,131 @@ use tokio::{
 
 pub fn parse_entry(
     file: DirEntry,
-    tx: UnboundedSender<DocumentRef>,
+    tx: UnboundedSender<(String, Vec<DocumentRef>)>,
     index: Arc<Mutex<HashMap<String, u64>>>,
     task_list: &mut Vec<JoinHandle<()>>,
-    tx_m: UnboundedSender<EncodingRequest>,
+    tx_m: EncodingSender,
+    splitter: SplitterConfig,
+    collection: String,
 ) {
     task_list.push(spawn(async move {
         let path = file.path();
-        let last_modified = get_last_modified(&path).unwrap();
+        // Editing a sidecar should re-index the file it describes
+        let last_modified = get_last_modified(&path)
+            .unwrap()
+            .max(get_last_modified(&sidecar_path(&path)).unwrap_or(0));
         let to_read_file = {
             let guard = index.lock().unwrap();
             last_modified > guard.get(path.to_str().unwrap()).unwrap_or(&0).to_owned()
         };
         if to_read_file {
-            create_embeddings_from_file(&path, tx_m)
-                .await
-                .unwrap()
-                .iter()
-                .for_each(|embeddings| tx.send(embeddings.clone()).unwrap());
-            {
-                let mut guard = index.lock().unwrap();
-                update_index(&path, &mut guard);
-            };
+            // Files that fail are left out of the index so they are retried on the next run,
+            // `store_entries` adds the ones it stored
+            match create_embeddings_from_file(&path, tx_m, &splitter, &collection).await {
+                // Closed when storing gave up, the file is retried like any other failure
+                Ok(documents) => {
+                    let _ = tx.send((path.to_str().unwrap().to_string(), documents));
+                }
+                Err(e) => eprintln!("Failed to index {path:?}: {e}"),
+            }
         }
     }));
 }
 
 async fn create_embeddings_from_file(
     path: &PathBuf,
-    tx_m: UnboundedSender<EncodingRequest>,
+    tx_m: EncodingSender,
+    splitter: &SplitterConfig,
+    collection: &str,
 ) -> Result<Vec<DocumentRef>, Box<dyn Error>> {
     if path.extension().is_none() {
         eprintln!("Invalid file {path:?}");
         return Ok(vec![]);
     }
-    let input = match path.extension().unwrap().to_str().unwrap() {
+    let extension = path.extension().unwrap().to_str().unwrap();
+    let extracted = match extension {
         "pdf" => read_chars_from_pdf(path)?,
-        "txt" | "md" => read_chars_form_text_file(path)?,
+        "txt" | "md" => read_chars_form_text_file(path, extension == "md")?,
         "wav" | "mp3" | "mp4" | "aac" => read_chars_from_audio(path).await?,
         _ => {
             eprintln!("Invalid file {path:?}");
-            vec![]
+            Extracted::default()
         }
     };
-    if input.len() == 0 {
+    let input = extracted.chars.as_slice();
+    let body = &input[extracted.body_start..];
+    if body.iter().all(|c| c.is_whitespace()) {
         return Ok(vec![]);
     }
-    let parsed_input: Vec<String> =
-        TextSplitter::new(input.as_slice(), 512 * 4, Some("\n\n")).collect();
-    let (tx, rx) = oneshot::channel();
-    let _ = tx_m.send(EncodingRequest {
-        raw: parsed_input.clone(),
-        tx,
-    });
-    let embeddings = rx.await.unwrap();
+    let mut metadata = extracted.metadata;
+    metadata.extend(read_sidecar_metadata(path)?);
+    // Filters rely on these, so neither front-matter nor a sidecar gets to set them
+    metadata.insert("file_type".to_string(), Value::from(extension));
+    metadata.insert(
+        "file_name".to_string(),
+        Value::from(path.file_name().and_then(|f| f.to_str()).unwrap_or("")),
+    );
+    metadata.insert(
+        "modified".to_string(),
+        Value::from(get_last_modified(path)?),
+    );
+
+    let chunks: Vec<Chunk> = if splitter.semantic.iter().any(|ext| ext == extension) {
+        SemanticSplitter::new(
+            splitter.threshold,
+            splitter.min_chunk,
+            splitter.max_chunk,
+            splitter.window,
+        )
+        .split(body, tx_m.clone())
+        .await?
+    } else {
+        TextSplitter::new(body, splitter.max_chunk, Some("\n\n")).collect()
+    }
+    .into_iter()
+    .map(|chunk| Chunk {
+        start: chunk.start + extracted.body_start,
+        end: chunk.end + extracted.body_start,
+        ..chunk
+    })
+    .collect();
+    // Line numbers only make sense for files that are read as is
+    let lines = match extension {
+        "txt" | "md" => Some(LineIndex::new(input)),
+        _ => None,
+    };
+    let embeddings = encode_batched(
+        &tx_m,
+        chunks.iter().map(|chunk| chunk.text.clone()).collect(),
+    )
+    .await?;
     Ok(embeddings
         .iter()
+        .zip(chunks)
         .enumerate()
-        .map(|(i, embedding)| DocumentRef {
+        .map(|(i, (embedding, chunk))| DocumentRef {
+            collection: collection.to_string(),
+            metadata: {
+                let mut metadata = metadata.clone();
+                if !extracted.pages.is_empty() {
+                    let page = extracted.pages.partition_point(|p| *p <= chunk.start);
+                    metadata.insert("page".to_string(), Value::from(page));
+                }
+                Value::Object(metadata)
+            },
             embedding: embedding.clone(),
-            raw: parsed_input[i].clone(),
             doc_ref: path.to_str().unwrap().to_string(),
             segment: i as i64,
             relevence: None,
+            start_offset: Some(chunk.start as i64),
+            end_offset: Some(chunk.end as i64),
+            start_line: lines.as_ref().map(|l| l.line(chunk.start) as i32),
+            end_line: lines
+                .as_ref()
+                .map(|l| l.line(chunk.end.max(chunk.start + 1) - 1) as i32),
+            embedding_model: Some(tx_m.model.clone()),
+            embedding_dim: Some(tx_m.dimension as i32),
+            raw: chunk.text,
         })
         .collect())
 }
@@ -106,28 +178,120 @@ fn update_index(path: &PathBuf, index: &mut HashMap<String, u64>) {
     );
 }
 
-fn read_chars_form_text_file(path: &PathBuf) -> Result<Vec<char>, Box<dyn Error>> {
+#[derive(Default)]
+struct Extracted {
+    chars: Vec<char>,
+    // Offset of the text to index, anything before it (e.g. front-matter) is only metadata
+    body_start: usize,
+    // Offsets at which each page starts, for paged formats
+    pages: Vec<usize>,
+    metadata: Map<String, Value>,
+}
+
+fn read_chars_form_text_file(
+    path: &PathBuf,
+    front_matter: bool,
+) -> Result<Extracted, Box<dyn Error>> {
     let mut file = BufReader::new(fs::File::open(path.clone())?);
     let mut input = String::new();
     file.read_to_string(&mut input)?;
-    Ok(input.chars().collect())
+    let (body_start, metadata) = if front_matter {
+        parse_front_matter(&input)
+    } else {
+        (0, Map::new())
+    };
+    Ok(Extracted {
+        chars: input.chars().collect(),
+        body_start,
+        metadata,
+        ..Default::default()
+    })
+}
+
+// Reads flat `key: value` pairs from a `---` delimited block at the top of a Markdown file.
+// Returns the character offset at which the body starts.
+fn parse_front_matter(input: &str) -> (usize, Map<String, Value>) {
+    let mut metadata = Map::new();
+    let rest = match input.strip_prefix("---\n") {
+        Some(rest) => rest,
+        None => return (0, metadata),
+    };
+    let (block, end) = match rest.find("\n---") {
+        Some(end) => (&rest[..end], "---\n".len() + end + "\n---".len()),
+        None => return (0, metadata),
+    };
+    for line in block.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) if !key.trim().is_empty() => (key.trim(), value.trim()),
+            _ => continue,
+        };
+        metadata.insert(key.to_string(), front_matter_value(value));
+    }
+    let end = if input[end..].starts_with('\n') {
+        end + 1
+    } else {
+        end
+    };
+    (input[..end].chars().count(), metadata)
+}
+
+fn front_matter_value(value: &str) -> Value {
+    if let Some(list) = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+        return Value::Array(
+            list.split(',')
+                .map(|v| front_matter_value(v.trim()))
+                .collect(),
+        );
+    }
+    if let Ok(value) = serde_json::from_str::<Value>(value) {
+        if !value.is_object() && !value.is_array() {
+            return value;
+        }
+    }
+    Value::from(value.trim_matches(|c| c == '"' || c == '\''))
+}
+
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.meta.json", path.to_str().unwrap()))
+}
+
+// Metadata from `<file>.meta.json` next to the file, it overrides front-matter but not the keys
+// every segment gets
+fn read_sidecar_metadata(path: &Path) -> Result<Map<String, Value>, Box<dyn Error>> {
+    let sidecar = sidecar_path(path);
+    if fs::metadata(&sidecar).is_err() {
+        return Ok(Map::new());
+    }
+    match serde_json::from_reader(BufReader::new(fs::File::open(&sidecar)?))? {
+        Value::Object(metadata) => Ok(metadata),
+        _ => Err(format!("{sidecar:?} must contain a JSON object").into()),
+    }
 }
 
-fn read_chars_from_pdf(path: &PathBuf) -> Result<Vec<char>, Box<dyn Error>> {
+fn read_chars_from_pdf(path: &PathBuf) -> Result<Extracted, Box<dyn Error>> {
     let doc = PopplerDocument::new_from_file(path, "")?;
-    let mut contents = Vec::new();
+    let mut extracted = Extracted::default();
     for i in 0..doc.get_n_pages() {
+        extracted.pages.push(extracted.chars.len());
         if let Some(page) = doc.get_page(i) {
             if let Some(content) = page.get_text() {
                 let mut chars: Vec<char> = content.chars().collect();
-                contents.append(&mut chars);
+                extracted.chars.append(&mut chars);
             }
         };
     }
-    Ok(contents)
+    if let Some(title) = doc.get_title() {
+        extracted
+            .metadata
+            .insert("title".to_string(), Value::from(title));
+    }
+    extracted
+        .metadata
+        .insert("pages".to_string(), Value::from(doc.get_n_pages()));
+    Ok(extracted)
 }
 
-async fn read_chars_from_audio(path: &PathBuf) -> Result<Vec<char>, Box<dyn Error>> {
+async fn read_chars_from_audio(path: &PathBuf) -> Result<Extracted, Box<dyn Error>> {
     // TODO: Look for an local alterntive
     const TRANSCRIPTION_URI: &str = "https://api.openai.com/v1/audio/transcriptions";
     let token = std::env::var("OPEN_AI_TOKEN").unwrap();
@@ -153,7 +317,10 @@ async fn read_chars_from_audio(path: &PathBuf) -> Result<Vec<char>, Box<dyn Erro
         .get("text")
         .and_then(|v| v.as_str())
         .unwrap_or("");
-    Ok(content.chars().collect())
+    Ok(Extracted {
+        chars: content.chars().collect(),
+        ..Default::default()
+    })
 }
 
 fn get_last_modified(path: &PathBuf) -> Result<u64, Box<dyn Error>> {
@@ -161,58 +328,170 @@ fn get_last_modified(path: &PathBuf) -> Result<u64, Box<dyn Error>> {
     Ok(metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs())
 }
 
-pub async fn store_entries(mut rx: UnboundedReceiver<DocumentRef>, pool: Pool<Postgres>) {
-    while let Some(msg) = rx.recv().await {
-        sqlx::query(
-            "INSERT INTO documents (embedding, raw, doc_ref, segment) VALUES ($1, $2, $3, $4)",
+// Replaces everything stored for a file in a collection with its new segments. Runs alone, so
+// near-duplicates are checked against every file stored before.
+pub async fn store_entries(
+    mut rx: UnboundedReceiver<(String, Vec<DocumentRef>)>,
+    store: Arc<dyn VectorStore>,
+    config: CollectionConfig,
+    index: Arc<Mutex<HashMap<String, u64>>>,
+) -> Result<IngestStats, StoreError> {
+    let mut stats = IngestStats::default();
+    let mut seen = match config.dedup.mode {
+        DedupMode::Off => None,
+        _ => Some(Deduplicator::load(store.as_ref(), &config.name, config.dedup.distance).await?),
+    };
+    while let Some((doc_ref, documents)) = rx.recv().await {
+        stats.files += 1;
+        // Files that fail keep their old segments and stay out of the index, so they are retried
+        // on the next run
+        match store_entry(
+            store.as_ref(),
+            &config,
+            &doc_ref,
+            documents,
+            seen.as_mut(),
+            &index,
+            &mut stats,
         )
-        .bind(msg.embedding.clone())
-        .bind(msg.raw.clone())
-        .bind(msg.doc_ref.clone())
-        .bind(msg.segment)
-        .execute(&pool)
         .await
-        .unwrap();
+        {
+            Ok(()) => update_index(&PathBuf::from(&doc_ref), &mut index.lock().unwrap()),
+            Err(e) => eprintln!("Failed to store {doc_ref}: {e}"),
+        }
     }
+    Ok(stats)
+}
+
+async fn store_entry(
+    store: &dyn VectorStore,
+    config: &CollectionConfig,
+    doc_ref: &str,
+    mut documents: Vec<DocumentRef>,
+    seen: Option<&mut Deduplicator>,
+    index: &Mutex<HashMap<String, u64>>,
+    stats: &mut IngestStats,
+) -> Result<(), StoreError> {
+    let collection = &config.name;
+    if let Some(seen) = seen {
+        let previous = seen.remove_doc(doc_ref);
+        if config.dedup.mode == DedupMode::Merge {
+            // References merged into the old segments go with them, so the files they point
+            // at are ingested again on the next run
+            let previous = store.segments(collection, doc_ref, &previous).await?;
+            {
+                let mut index = index.lock().unwrap();
+                for source in referenced_docs(&previous) {
+                    index.insert(source, 0);
+                }
+            }
+            store.remove_duplicates(collection, doc_ref).await?;
+        }
+        documents = dedupe(store, collection, documents, seen, &config.dedup, stats).await?;
+    }
+    let segments = documents.len();
+    store.replace_doc(collection, doc_ref, documents).await?;
+    stats.segments += segments;
+    Ok(())
 }
 
 pub async fn store_data(
-    pool: Pool<Postgres>,
-    tx_m: UnboundedSender<EncodingRequest>,
-    config: &Config,
+    store: Arc<dyn VectorStore>,
+    tx_m: EncodingSender,
+    config: &CollectionConfig,
 ) -> Result<(), Box<dyn Error>> {
-    if fs::metadata(&config.index).is_err() {
-        fs::File::create(&config.index)?;
-    }
-    let index: Arc<Mutex<HashMap<String, u64>>> = Arc::new(Mutex::new(serde_json::from_reader(
-        BufReader::new(fs::File::open(config.index.clone())?),
-    )?));
-    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<DocumentRef>();
+    // A store that starts out empty needs every file, whatever the index says
+    let index = if !store.persistent() {
+        HashMap::new()
+    } else if let Some(index) = store
+        .load_index(&config.name)
+        .await
+        .map_err(|e| e.to_string())?
+    {
+        index
+    } else {
+        if fs::metadata(&config.index).is_err() {
+            fs::File::create(&config.index)?;
+        }
+        serde_json::from_reader(BufReader::new(fs::File::open(config.index.clone())?))?
+    };
+    let index: Arc<Mutex<HashMap<String, u64>>> = Arc::new(Mutex::new(index));
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<(String, Vec<DocumentRef>)>();
+    let cache = tx_m.cache.clone();
     let mut tasks = Vec::new();
-    fs::read_dir(&config.path)?.for_each(|entry| {
+    for entry in fs::read_dir(&config.path)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!("Unable to read an entry of {}: {e}", config.path);
+                continue;
+            }
+        };
+        // Sub-directories aren't walked, and sidecars are read along with the file they describe
+        if !entry.file_type().is_ok_and(|t| t.is_file())
+            || entry.file_name().to_string_lossy().ends_with(".meta.json")
+        {
+            continue;
+        }
         parse_entry(
-            entry.unwrap(),
+            entry,
             tx.clone(),
             index.clone(),
             &mut tasks,
             tx_m.clone(),
+            config.splitter.clone(),
+            config.name.clone(),
         );
-    });
+    }
     drop(tx);
     drop(tx_m);
-    tasks.push(spawn(async move {
-        store_entries(rx, pool).await;
-    }));
+    let entries = spawn(store_entries(
+        rx,
+        store.clone(),
+        config.clone(),
+        index.clone(),
+    ));
     join_all(tasks).await;
-    serde_json::to_writer(
-        BufWriter::new(fs::File::create(config.index.clone())?),
-        &index,
-    )?;
+    let stats = entries.await?.map_err(|e| e.to_string())?;
+    match config.dedup.mode {
+        DedupMode::Off => println!(
+            "{}: stored {} segments of {} files",
+            config.name, stats.segments, stats.files
+        ),
+        DedupMode::Skip => println!(
+            "{}: stored {} segments of {} files, skipped {} near-duplicates",
+            config.name, stats.segments, stats.files, stats.duplicates
+        ),
+        DedupMode::Merge => println!(
+            "{}: stored {} segments of {} files, merged {} near-duplicates into the segments they repeat",
+            config.name, stats.segments, stats.files, stats.duplicates
+        ),
+    }
+    if let Some(cache) = &cache {
+        let stats = cache.stats();
+        println!(
+            "Embedding cache: {} hits, {} misses",
+            stats.hits, stats.misses
+        );
+    }
+    let index = index.lock().unwrap().clone();
+    // Nothing to remember for a store that starts out empty again
+    if store.persistent()
+        && !store
+            .save_index(&config.name, &index)
+            .await
+            .map_err(|e| e.to_string())?
+    {
+        serde_json::to_writer(
+            BufWriter::new(fs::File::create(config.index.clone())?),
+            &index,
+        )?;
+    }
 
     Ok(())
 }
 
-pub fn euclidean_distance(point1: &Vec<f32>, point2: &Vec<f32>) -> f32 {
+pub fn euclidean_distance(point1: &[f32], point2: &[f32]) -> f32 {
     assert_eq!(point1.len(), point2.len());
     point1
         .iter()
@@ -221,42 +500,346 @@ pub fn euclidean_distance(point1: &Vec<f32>, point2: &Vec<f32>) -> f32 {
         .powf(0.5)
 }
 
-pub fn sort_embeddings(embeddings: Vec<DocumentRef>) -> Vec<String> {
-    let mut documents = Vec::new();
-    for doc in embeddings {
-        documents.push(doc.clone());
+pub fn dot_product(point1: &[f32], point2: &[f32]) -> f32 {
+    assert_eq!(point1.len(), point2.len());
+    point1.iter().zip(point2).map(|(a, b)| a * b).sum()
+}
+
+pub fn cosine_similarity(point1: &[f32], point2: &[f32]) -> f32 {
+    let dot = dot_product(point1, point2);
+    let norm1 = point1.iter().map(|v| v * v).sum::<f32>().sqrt();
+    let norm2 = point2.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm1 == 0f32 || norm2 == 0f32 {
+        return 0f32;
     }
-    // TODO: If two documents have similar relevency and have close by segments, further sort them
-    // in increasing order of segment
-    documents.sort_by(|a, b| {
-        b.relevence
-            .unwrap()
-            .partial_cmp(&a.relevence.unwrap())
-            .unwrap()
-    });
-    documents.iter().map(|v| v.raw.clone()).collect()
+    dot / (norm1 * norm2)
+}
+
+// What the embedding cache tells vectors apart by. An OpenAI compatible endpoint can serve
+// another model under the same name, or shorten vectors on request.
+fn cache_source(config: &EmbeddingConfig, dimension: usize) -> String {
+    match config.backend {
+        EmbeddingBackend::RustBert => format!("rust-bert\0{}\0{dimension}", config.model),
+        EmbeddingBackend::OpenAi => {
+            format!("openai\0{}\0{dimension}\0{}", config.model, config.url)
+        }
+    }
+}
+
+pub fn encoding_channel(
+    config: &EmbeddingConfig,
+    dimension: usize,
+    cache: Option<Arc<EmbeddingCache>>,
+    normalize: bool,
+) -> (EncodingSender, EncodingReceiver) {
+    let (query_tx, query_rx) = mpsc::channel(config.queue_size);
+    let (ingest_tx, ingest_rx) = mpsc::channel(config.queue_size);
+    let health = Arc::new(RwLock::new(WorkerStatus::Starting));
+    (
+        EncodingSender {
+            query: query_tx,
+            ingest: ingest_tx,
+            batch_size: config.batch_size.max(1),
+            health: health.clone(),
+            model: config.model.clone(),
+            dimension,
+            cache_source: cache_source(config, dimension),
+            cache,
+            normalize,
+        },
+        EncodingReceiver {
+            query: query_rx,
+            ingest: ingest_rx,
+            pending: None,
+            health,
+        },
+    )
 }
 
-pub fn spawn_embedding_model(mut rx: UnboundedReceiver<EncodingRequest>) {
+// Waits for the next request and coalesces whatever else is queued behind it into a batch of at
+// most `batch_size` texts. Query requests are never mixed with ingestion requests.
+async fn next_batch(rx: &mut EncodingReceiver, batch_size: usize) -> Option<Vec<EncodingRequest>> {
+    let mut batch = Vec::new();
+    let from_query = match rx.pending.take() {
+        Some((true, request)) => {
+            batch.push(request);
+            true
+        }
+        // Questions that arrived meanwhile still go ahead of a left over ingestion request
+        Some((false, request)) => match rx.query.try_recv() {
+            Ok(query) => {
+                rx.pending = Some((false, request));
+                batch.push(query);
+                true
+            }
+            Err(_) => {
+                batch.push(request);
+                false
+            }
+        },
+        None => tokio::select! {
+            biased;
+            Some(request) = rx.query.recv() => {
+                batch.push(request);
+                true
+            }
+            Some(request) = rx.ingest.recv() => {
+                batch.push(request);
+                false
+            }
+            else => return None,
+        },
+    };
+    let mut size: usize = batch.iter().map(|r| r.raw.len()).sum();
+    while size < batch_size {
+        let queue = if from_query {
+            &mut rx.query
+        } else {
+            &mut rx.ingest
+        };
+        let request = match queue.try_recv() {
+            Ok(request) => request,
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => break,
+        };
+        if size + request.raw.len() > batch_size {
+            rx.pending = Some((from_query, request));
+            break;
+        }
+        size += request.raw.len();
+        batch.push(request);
+    }
+    Some(batch)
+}
+
+/// Runs the model on a blocking thread. If loading the model fails or it panics the worker marks
+/// itself as degraded and restarts with a fresh model after a backoff; queued requests wait.
+pub fn spawn_embedding_model(mut rx: EncodingReceiver, config: EmbeddingConfig) {
+    let handle = Handle::current();
     spawn_blocking(move || {
-        let model = SentenceEmbeddingsBuilder::remote(SentenceEmbeddingsModelType::AllMiniLmL6V2)
-            .create_model()
-            .unwrap();
-        while let Some(msg) = rx.blocking_recv() {
-            let embeddings = model.encode(&msg.raw).expect("Failed to encode");
-            let _ = msg.tx.send(embeddings);
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                run_embedding_model(&handle, &mut rx, &config, &mut backoff)
+            }));
+            let error = match result {
+                Ok(Ok(())) => return,
+                Ok(Err(error)) => error,
+                Err(panic) => panic
+                    .downcast_ref::<&str>()
+                    .map(|e| e.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or("Embedding worker panicked".to_string()),
+            };
+            eprintln!("Embedding worker failed, restarting in {backoff:?}: {error}");
+            *rx.health.write().unwrap() = WorkerStatus::Degraded(error);
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(Duration::from_secs(60));
         }
     });
 }
 
+// Returns once both queues are closed, or with the error that should restart the worker. Only
+// loading is retried, a batch that fails to encode (e.g. a rate limited API) fails on its own.
+fn run_embedding_model(
+    handle: &Handle,
+    rx: &mut EncodingReceiver,
+    config: &EmbeddingConfig,
+    backoff: &mut Duration,
+) -> Result<(), String> {
+    let model = create_embedder(config, handle)
+        .map_err(|e| format!("Failed to load embedding model {}: {e}", config.model))?;
+    *rx.health.write().unwrap() = WorkerStatus::Healthy;
+    *backoff = Duration::from_secs(1);
+    while let Some(batch) = handle.block_on(next_batch(rx, config.batch_size)) {
+        let raw: Vec<&str> = batch
+            .iter()
+            .flat_map(|r| r.raw.iter().map(|raw| raw.as_str()))
+            .collect();
+        let mut embeddings = match model.embed(&raw) {
+            Ok(embeddings) => embeddings.into_iter(),
+            Err(e) => {
+                let error = format!("Failed to encode: {e}");
+                eprintln!("{error}");
+                for request in batch {
+                    let _ = request.tx.send(Err(error.clone()));
+                }
+                continue;
+            }
+        };
+        for request in batch {
+            let _ = request
+                .tx
+                .send(Ok(embeddings.by_ref().take(request.raw.len()).collect()));
+        }
+    }
+    Ok(())
+}
+
+/// Encodes texts on the ingestion queue, split into batches the worker can take in one go. Waits
+/// for room in the queue, so producers slow down to the speed of the model.
+pub async fn encode_batched(
+    sender: &EncodingSender,
+    raw: Vec<String>,
+) -> Result<Vec<Vec<f32>>, Box<dyn Error>> {
+    let mut embeddings = cached_embeddings(sender, &raw).await;
+    let missing: Vec<usize> = (0..raw.len())
+        .filter(|i| embeddings[*i].is_none())
+        .collect();
+    let to_encode: Vec<String> = missing.iter().map(|i| raw[*i].clone()).collect();
+    let mut receivers = Vec::new();
+    for batch in to_encode.chunks(sender.batch_size) {
+        let (tx, rx) = oneshot::channel();
+        sender
+            .ingest
+            .send(EncodingRequest {
+                raw: batch.to_vec(),
+                tx,
+            })
+            .await
+            .map_err(|_| "Embedding worker is not running")?;
+        receivers.push(rx);
+    }
+    let mut encoded = Vec::with_capacity(to_encode.len());
+    for rx in receivers {
+        encoded.extend(rx.await??);
+    }
+    cache_embeddings(sender, &to_encode, &encoded).await;
+    for (i, embedding) in missing.into_iter().zip(encoded) {
+        embeddings[i] = Some(embedding);
+    }
+    Ok(embeddings
+        .into_iter()
+        .map(|e| normalized(sender, e.unwrap()))
+        .collect())
+}
+
+// The cache keeps vectors as the model made them, so normalising happens on the way out
+fn normalized(sender: &EncodingSender, mut embedding: Vec<f32>) -> Vec<f32> {
+    if sender.normalize {
+        let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm > 0f32 {
+            embedding.iter_mut().for_each(|v| *v /= norm);
+        }
+    }
+    embedding
+}
+
+// The cache is an optimisation, when it can't be reached everything is encoded as if it missed
+async fn cached_embeddings(sender: &EncodingSender, raw: &[String]) -> Vec<Option<Vec<f32>>> {
+    match &sender.cache {
+        Some(cache) => cache
+            .get(&sender.cache_source, raw)
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to read embedding cache: {e}");
+                vec![None; raw.len()]
+            }),
+        None => vec![None; raw.len()],
+    }
+}
+
+async fn cache_embeddings(sender: &EncodingSender, raw: &[String], embeddings: &[Vec<f32>]) {
+    if let Some(cache) = &sender.cache {
+        if let Err(e) = cache
+            .put(&sender.model, &sender.cache_source, raw, embeddings)
+            .await
+        {
+            eprintln!("Failed to write embedding cache: {e}");
+        }
+    }
+}
+
 pub async fn generate_embedding_for_text(
-    sender: UnboundedSender<EncodingRequest>,
+    sender: EncodingSender,
     prompt: String,
 ) -> Result<Vec<f32>, Box<dyn Error>> {
+    // Fail fast instead of queueing questions behind a worker that is restarting
+    if let WorkerStatus::Degraded(error) = sender.status() {
+        return Err(format!("Embedding worker is degraded: {error}").into());
+    }
+    let raw = vec![prompt];
+    if let Some(embedding) = cached_embeddings(&sender, &raw).await.remove(0) {
+        return Ok(normalized(&sender, embedding));
+    }
     let (tx, rx) = tokio::sync::oneshot::channel();
-    let _ = sender.send(EncodingRequest {
-        raw: vec![prompt],
-        tx,
-    });
-    Ok(rx.await?[0].clone())
+    sender
+        .query
+        .send(EncodingRequest {
+            raw: raw.clone(),
+            tx,
+        })
+        .await
+        .map_err(|_| "Embedding worker is not running")?;
+    let embeddings = rx.await??;
+    cache_embeddings(&sender, &raw, &embeddings).await;
+    Ok(normalized(&sender, embeddings[0].clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        cli::{DedupConfig, RetrievalConfig},
+        store::{memory::MemoryStore, Distance},
+    };
+
+    #[test]
+    fn front_matter_is_read_and_skipped() {
+        let input = "---\ntitle: Urlaub für alle\nyear: 2023\ndraft: false\nowner: 'HR'\n\
+                     tags: [hr, \"policy\", 2]\nno value here\n: no key\n---\nBody ü";
+        let (offset, metadata) = parse_front_matter(input);
+        assert_eq!(input.chars().skip(offset).collect::<String>(), "Body ü");
+        assert_eq!(
+            Value::Object(metadata),
+            json!({
+                "title": "Urlaub für alle",
+                "year": 2023,
+                "draft": false,
+                "owner": "HR",
+                "tags": ["hr", "policy", 2],
+            })
+        );
+    }
+
+    #[test]
+    fn front_matter_needs_a_closed_block_at_the_top() {
+        for input in ["# Title\n---\nkey: value\n---\n", "---\nkey: value\n", ""] {
+            let (offset, metadata) = parse_front_matter(input);
+            assert_eq!((offset, metadata.len()), (0, 0), "{input:?}");
+        }
+        let (offset, metadata) = parse_front_matter("---\nkey: value\n---");
+        assert_eq!((offset, metadata.len()), (18, 1));
+    }
+
+    #[tokio::test]
+    async fn memory_store_ingests_without_an_index_file() {
+        let dir = std::env::temp_dir().join(format!("llm-chatbot-ingest-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "Cats purr. Cats nap.").unwrap();
+        fs::create_dir_all(dir.join("drafts")).unwrap();
+        let (tx, mut rx) = encoding_channel(&EmbeddingConfig::default(), 2, None, false);
+        tokio::spawn(async move {
+            while let Some(request) = rx.ingest.recv().await {
+                let embeddings = request.raw.iter().map(|_| vec![1f32, 0f32]).collect();
+                let _ = request.tx.send(Ok(embeddings));
+            }
+        });
+        let store = Arc::new(MemoryStore::default());
+        let config = CollectionConfig {
+            name: "default".to_string(),
+            path: dir.to_string_lossy().to_string(),
+            index: String::new(),
+            splitter: SplitterConfig::default(),
+            dedup: DedupConfig::default(),
+            embedding: EmbeddingConfig::default(),
+            distance: Distance::default(),
+            retrieval: RetrievalConfig::default(),
+        };
+        let stored = store_data(store.clone(), tx, &config).await;
+        fs::remove_dir_all(&dir).unwrap();
+        stored.unwrap();
+        assert_eq!(store.count().await.unwrap(), 1);
+    }
 }